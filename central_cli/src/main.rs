@@ -11,6 +11,8 @@ cli! {
     Central.[
         /// Central services status checks
         Status => check_status,
+        /// Request counts, errors and latencies of the running providers
+        Metrics => show_metrics,
         /// Manages the raw note data. Use with care
         Note.[
             /// Creates a new note and saves it to central
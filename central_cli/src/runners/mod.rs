@@ -5,6 +5,8 @@ use std::io::BufRead;
 use std::process::Command;
 use std::time::{Duration, Instant};
 
+use futures::StreamExt;
+
 use tokio::time::timeout;
 
 use rayon::prelude::*;
@@ -15,7 +17,7 @@ use note_store::status_client::{Check, NoteStoreStatusClient};
 
 use utils::*;
 
-use registry::{interface, StatusClient};
+use registry::{interface, MetricsClient, StatusClient};
 
 interface! {
     NoteCommand,
@@ -75,6 +77,18 @@ pub fn check_status() {
     });
 }
 
+pub fn show_metrics() {
+    actix_rt::System::new("main").block_on(async move {
+        for provider in &["note_store", "note_repo", "project_store"] {
+            match timeout(Duration::from_secs(1), MetricsClient::scrape(provider)).await {
+                Ok(Ok(text)) => println!("# {}\n{}", provider, text),
+                Ok(Err(e)) => println!("# {}: Error({})", provider, e),
+                Err(e) => println!("# {}: Error({})", provider, e),
+            }
+        }
+    });
+}
+
 pub fn create_note() {
     let editor = TmpEditor::new();
 
@@ -100,18 +114,18 @@ pub fn create_note() {
                 body: contents,
             })
             .await
-            .expect("Failed To Notify Note Sore");
+            .expect("Failed To Notify Note Sore")
+            .expect("Note Store couldn't apply the change");
     });
 }
 
 pub fn list_notes() {
     actix_rt::System::new("main").block_on(async move {
-        get_notes()
-            .await
-            .expect("Couldn't fetch notes")
-            .iter()
-            .map(|note| note.title.clone())
-            .for_each(|line| println!("{}", line));
+        let mut notes = stream_notes().await.expect("Couldn't fetch notes");
+
+        while let Some(note) = notes.next().await {
+            println!("{}", note.expect("Couldn't fetch notes").title);
+        }
     });
 }
 
@@ -123,7 +137,8 @@ pub fn delete_note() {
         note_client
             .send(Delete { reference })
             .await
-            .expect("Failed To Notify Note Sore");
+            .expect("Failed To Notify Note Sore")
+            .expect("Note Store couldn't apply the change");
     });
 }
 
@@ -151,6 +166,7 @@ pub fn update_note() {
                 body: contents,
             })
             .await
-            .expect("Failed To Notify Note Sore");
+            .expect("Failed To Notify Note Sore")
+            .expect("Note Store couldn't apply the change");
     });
 }
@@ -6,6 +6,8 @@ use actix::Addr;
 
 use failure::Error;
 
+use futures::TryStreamExt;
+
 use dialoguer::Select;
 
 use tempfile::{Builder, NamedTempFile};
@@ -13,6 +15,8 @@ use tempfile::{Builder, NamedTempFile};
 use note_store::model::{NoteDescriptor, NoteRef};
 use note_store::query_client::{GetContent, GetIndex, NoteQueryClient};
 
+use registry::cliff::client::Streaming;
+
 registry::interface! {
     NoteQuery,
 }
@@ -57,16 +61,17 @@ impl TmpEditor {
     }
 }
 
-pub async fn get_notes() -> Result<Vec<NoteDescriptor>, Error> {
+/// The stored notes, as the provider finds them
+pub async fn stream_notes() -> Result<Streaming<NoteDescriptor>, Error> {
     let query_client = require::<NoteQueryClient>().await?;
 
-    get_notes_from_client(&query_client).await
+    Ok(query_client.send(GetIndex).await?)
 }
 
 async fn get_notes_from_client(
     client: &Addr<NoteQueryClient>,
 ) -> Result<Vec<NoteDescriptor>, Error> {
-    Ok(client.send(GetIndex).await??)
+    client.send(GetIndex).await?.try_collect().await
 }
 
 pub async fn select_note() -> Result<(NoteRef, String), Error> {
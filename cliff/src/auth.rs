@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+/// Who's on the other end of a connection, as the kernel tells it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only known where the platform hands it out along with the ids
+    pub pid: Option<i32>,
+}
+
+impl Credentials {
+    /// This process's own, which is also what whoever spawned it on stdio runs as
+    pub fn own() -> Self {
+        // Safe: these can't fail
+        unsafe {
+            Credentials {
+                uid: libc::geteuid(),
+                gid: libc::getegid(),
+                pid: Some(libc::getpid()),
+            }
+        }
+    }
+}
+
+/// Who may call a method
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Allow {
+    Anyone,
+    /// Peers the kernel vouches for, which leaves out those over tcp
+    Local,
+    /// Peers running as the same user as the server
+    Owner,
+    Users(Vec<u32>),
+    Groups(Vec<u32>),
+    Nobody,
+}
+
+impl Allow {
+    /// Peers without credentials, like those over tcp, are only let in by `Anyone`
+    pub fn permits(&self, peer: Option<&Credentials>) -> bool {
+        match (self, peer) {
+            (Allow::Anyone, _) => true,
+            (_, None) | (Allow::Nobody, _) => false,
+            (Allow::Local, Some(_)) => true,
+            (Allow::Owner, Some(peer)) => peer.uid == Credentials::own().uid,
+            (Allow::Users(uids), Some(peer)) => uids.contains(&peer.uid),
+            (Allow::Groups(gids), Some(peer)) => gids.contains(&peer.gid),
+        }
+    }
+}
+
+/// Who may call a router, method by method, e.g.
+/// `Policy::new(Allow::Local).method("Delete", Allow::Owner)`. Routers only answer local peers
+/// unless told otherwise
+#[derive(Clone, Debug)]
+pub struct Policy {
+    router: Allow,
+    methods: HashMap<String, Allow>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::new(Allow::Local)
+    }
+}
+
+impl Policy {
+    /// Applies `router` to every method without a rule of its own
+    pub fn new(router: Allow) -> Self {
+        Policy {
+            router,
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn method(mut self, method: &str, allow: Allow) -> Self {
+        self.methods.insert(method.to_string(), allow);
+        self
+    }
+
+    pub fn permits(&self, method: &str, peer: Option<&Credentials>) -> bool {
+        self.methods
+            .get(method)
+            .unwrap_or(&self.router)
+            .permits(peer)
+    }
+
+    /// Whether peers without credentials, like those over tcp, may call any method at all
+    pub fn admits_anonymous(&self) -> bool {
+        self.router == Allow::Anyone || self.methods.values().any(|allow| *allow == Allow::Anyone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(uid: u32, gid: u32) -> Credentials {
+        Credentials {
+            uid,
+            gid,
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn only_anyone_lets_in_peers_without_credentials() {
+        assert!(Allow::Anyone.permits(None));
+        for allow in &[
+            Allow::Local,
+            Allow::Owner,
+            Allow::Users(vec![0]),
+            Allow::Groups(vec![0]),
+            Allow::Nobody,
+        ] {
+            assert!(!allow.permits(None), "{:?}", allow);
+        }
+    }
+
+    #[test]
+    fn matches_local_peers_by_their_ids() {
+        let own = Credentials::own();
+        let stranger = peer(own.uid.wrapping_add(1), own.gid.wrapping_add(1));
+
+        assert!(Allow::Local.permits(Some(&stranger)));
+        assert!(Allow::Owner.permits(Some(&own)));
+        assert!(!Allow::Owner.permits(Some(&stranger)));
+        assert!(Allow::Users(vec![stranger.uid]).permits(Some(&stranger)));
+        assert!(!Allow::Users(vec![own.uid]).permits(Some(&stranger)));
+        assert!(Allow::Groups(vec![stranger.gid]).permits(Some(&stranger)));
+        assert!(!Allow::Groups(vec![own.gid]).permits(Some(&stranger)));
+        assert!(!Allow::Nobody.permits(Some(&own)));
+    }
+
+    #[test]
+    fn methods_override_the_router() {
+        let own = Credentials::own();
+        let stranger = peer(own.uid.wrapping_add(1), own.gid);
+        let policy = Policy::default().method("Delete", Allow::Owner);
+
+        assert!(policy.permits("Read", Some(&stranger)));
+        assert!(!policy.permits("Delete", Some(&stranger)));
+        assert!(policy.permits("Delete", Some(&own)));
+        assert!(!policy.permits("Read", None));
+    }
+
+    #[test]
+    fn admits_anonymous_peers_with_anyone_somewhere() {
+        assert!(!Policy::default().admits_anonymous());
+        assert!(Policy::new(Allow::Anyone).admits_anonymous());
+        assert!(Policy::new(Allow::Nobody)
+            .method("Ping", Allow::Anyone)
+            .admits_anonymous());
+    }
+}
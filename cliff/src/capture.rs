@@ -0,0 +1,669 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+
+use bytes::{Bytes, BytesMut};
+
+use failure::{format_err, Error};
+
+use futures::future::join_all;
+use futures::stream::StreamExt;
+
+use serde::de::DeserializeOwned;
+
+use tracing::{info, warn};
+
+use super::codec::{
+    Compression, DecodeError, Decoder, Encoder, ErrorResponse, Format, Framing, Id, Packet, Reply,
+};
+use super::context::{self, Request, RequestContext};
+use super::handshake::{Negotiated, Protocol};
+use super::server::{is_notification, Router, ServerRequest, ServerResponse};
+
+// A capture opens with a header describing the connection:
+// `CLIFCAP` | version | side | format | framing | max frame size (u32) |
+// compression | compression threshold (u32) | compression level (i32) | start in unix millis (u64)
+// followed by a record for every frame, in the order they were read or written:
+// direction | micros since the start (u64) | length (u32) | the frame as it was on the wire
+// Integers are big-endian, like frame lengths.
+const MAGIC: &[u8; 7] = b"CLIFCAP";
+const VERSION: u8 = 1;
+
+/// Environment variable naming the directory connections are captured to
+pub const CAPTURE_DIRECTORY_VAR: &str = "CAPTURE_DIRECTORY";
+
+static CAPTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Which end of the connection a capture was taken from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Server,
+    Client,
+}
+
+impl Side {
+    fn id(self) -> u8 {
+        match self {
+            Side::Server => 0,
+            Side::Client => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Side> {
+        match id {
+            0 => Some(Side::Server),
+            1 => Some(Side::Client),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Side::Server => "server",
+            Side::Client => "client",
+        }
+    }
+}
+
+/// Whether a frame was read or written by the side that captured it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// One frame of a capture
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub direction: Direction,
+    /// Time since the capture started
+    pub elapsed: Duration,
+    pub bytes: Bytes,
+}
+
+/// Records the frames of a single connection to a file
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<CaptureFile>>,
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl Capture {
+    pub fn create(path: &Path, side: Side, negotiated: Negotiated) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        // Thresholds and levels aren't part of the handshake, but replays need them to compress alike
+        let (threshold, level) = negotiated.compression.settings();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[
+            VERSION,
+            side.id(),
+            negotiated.format.id(),
+            negotiated.framing.id(),
+        ])?;
+        writer.write_all(&(max_frame_size(negotiated.framing) as u32).to_be_bytes())?;
+        writer.write_all(&[negotiated.compression.id()])?;
+        writer.write_all(&(threshold as u32).to_be_bytes())?;
+        writer.write_all(&level.to_be_bytes())?;
+        writer.write_all(&(started.as_millis() as u64).to_be_bytes())?;
+        writer.flush()?;
+
+        Ok(Capture {
+            file: Arc::new(Mutex::new(CaptureFile {
+                path: path.to_owned(),
+                writer,
+                started: Instant::now(),
+            })),
+        })
+    }
+
+    // A failing capture shouldn't take the connection down with it, so errors are only logged
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+
+        if let Err(e) = file.write_record(direction, frame) {
+            warn!(
+                "Couldn't record frame to {}: {}",
+                file.path.display(),
+                e.to_string()
+            );
+        }
+    }
+}
+
+impl CaptureFile {
+    // Flushed every time, so captures survive the crashes they're taken to investigate
+    fn write_record(&mut self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let elapsed = self.started.elapsed().as_micros() as u64;
+        let direction = match direction {
+            Direction::Inbound => 0u8,
+            Direction::Outbound => 1u8,
+        };
+
+        self.writer.write_all(&[direction])?;
+        self.writer.write_all(&elapsed.to_be_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+        self.writer.write_all(frame)?;
+        self.writer.flush()
+    }
+}
+
+/// Starts capturing a freshly negotiated connection if `protocol` asks for it, giving every
+/// connection its own file in the capture directory
+pub fn start(protocol: &Protocol, side: Side, negotiated: Negotiated) -> Option<Capture> {
+    let directory = protocol.capture.as_ref()?;
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis())
+        .unwrap_or(0);
+    let path = directory.join(format!(
+        "{}-{}-{}-{}.cap",
+        side.name(),
+        started,
+        process::id(),
+        CAPTURES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let capture = fs::create_dir_all(directory)
+        .map_err(Error::from)
+        .and_then(|_| Capture::create(&path, side, negotiated));
+    match capture {
+        Ok(capture) => {
+            info!("Capturing connection to {}", path.display());
+            Some(capture)
+        }
+        Err(e) => {
+            warn!(
+                "Couldn't capture connection to {}: {}",
+                path.display(),
+                e.to_string()
+            );
+            None
+        }
+    }
+}
+
+/// Passes frames through to the wrapped codec, recording each one when capturing
+pub struct Captured<C> {
+    inner: C,
+    capture: Option<Capture>,
+    // Mirrors the decoder's buffer, so the bytes of a frame are still around once it's consumed
+    unread: BytesMut,
+}
+
+impl<C> Captured<C> {
+    pub fn new(inner: C, capture: Option<Capture>) -> Self {
+        Captured {
+            inner,
+            capture,
+            unread: BytesMut::new(),
+        }
+    }
+
+    fn tap<T>(&mut self, src: &mut BytesMut, decode: impl FnOnce(&mut C, &mut BytesMut) -> T) -> T {
+        let capture = match &self.capture {
+            Some(capture) => capture,
+            None => return decode(&mut self.inner, src),
+        };
+
+        // Only what arrived since the last call gets copied
+        let seen = self.unread.len().min(src.len());
+        self.unread.extend_from_slice(&src[seen..]);
+
+        let before = src.len();
+        let result = decode(&mut self.inner, src);
+
+        let consumed = before - src.len();
+        if consumed > 0 {
+            capture.record(Direction::Inbound, &self.unread.split_to(consumed));
+        }
+
+        result
+    }
+}
+
+impl<C: tokio_util::codec::Decoder> tokio_util::codec::Decoder for Captured<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<C::Item>, C::Error> {
+        self.tap(src, |inner, src| inner.decode(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<C::Item>, C::Error> {
+        self.tap(src, |inner, src| inner.decode_eof(src))
+    }
+}
+
+impl<I, C: tokio_util::codec::Encoder<I>> tokio_util::codec::Encoder<I> for Captured<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), C::Error> {
+        let start = dst.len();
+        self.inner.encode(item, dst)?;
+
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Outbound, &dst[start..]);
+        }
+
+        Ok(())
+    }
+}
+
+/// A capture read back from disk
+#[derive(Clone, Debug)]
+pub struct Recording {
+    pub side: Side,
+    pub negotiated: Negotiated,
+    pub started: SystemTime,
+    pub frames: Vec<Frame>,
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(path.as_ref())?);
+
+        let mut header = [0u8; 32];
+        reader.read_exact(&mut header)?;
+
+        if &header[..7] != MAGIC {
+            return Err(format_err!("Not a capture: {}", path.as_ref().display()));
+        }
+        if header[7] != VERSION {
+            return Err(format_err!("Unsupported capture version: {}", header[7]));
+        }
+
+        let side = Side::from_id(header[8])
+            .ok_or_else(|| format_err!("Unknown capture side: {}", header[8]))?;
+        let format = Format::from_id(header[9])
+            .ok_or_else(|| format_err!("Format {} isn't available in this build", header[9]))?;
+        let framing = match header[10] {
+            0 => Framing::Stream,
+            1 => Framing::LengthDelimited {
+                max_frame_size: be_u32(&header[11..15]) as usize,
+            },
+            id => return Err(format_err!("Unknown framing: {}", id)),
+        };
+        let compression = Compression::from_settings(
+            header[15],
+            be_u32(&header[16..20]) as usize,
+            be_u32(&header[20..24]) as i32,
+        )
+        .ok_or_else(|| format_err!("Compression {} isn't available in this build", header[15]))?;
+        let started = UNIX_EPOCH + Duration::from_millis(be_u64(&header[24..32]));
+
+        let mut frames = vec![];
+        let mut record = [0u8; 13];
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            let direction = match record[0] {
+                0 => Direction::Inbound,
+                1 => Direction::Outbound,
+                other => return Err(format_err!("Unknown frame direction: {}", other)),
+            };
+
+            let mut bytes = vec![0u8; be_u32(&record[9..13]) as usize];
+            if let Err(e) = reader.read_exact(&mut bytes) {
+                // The process was likely killed while writing it
+                warn!("Capture ends partway through a frame: {}", e.to_string());
+                break;
+            }
+
+            frames.push(Frame {
+                direction,
+                elapsed: Duration::from_micros(be_u64(&record[1..9])),
+                bytes: Bytes::from(bytes),
+            });
+        }
+
+        Ok(Recording {
+            side,
+            negotiated: Negotiated {
+                format,
+                framing,
+                compression,
+            },
+            started,
+            frames,
+        })
+    }
+
+    /// Frames written by the client, whichever side took the capture
+    pub fn requests(&self) -> impl Iterator<Item = &Frame> {
+        let direction = match self.side {
+            Side::Server => Direction::Inbound,
+            Side::Client => Direction::Outbound,
+        };
+
+        self.frames.iter().filter(move |f| f.direction == direction)
+    }
+
+    /// Frames written by the server, whichever side took the capture
+    pub fn responses(&self) -> impl Iterator<Item = &Frame> {
+        let direction = match self.side {
+            Side::Server => Direction::Outbound,
+            Side::Client => Direction::Inbound,
+        };
+
+        self.frames.iter().filter(move |f| f.direction == direction)
+    }
+}
+
+/// A response that came out differently when replayed
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Position among the captured responses, or among the replayed ones if nothing was
+    /// captured for it
+    pub index: usize,
+    /// The requests it answers, more than one for a batch
+    pub ids: Vec<Id>,
+    pub captured: Option<Bytes>,
+    pub replayed: Option<Bytes>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<String> = self.ids.iter().map(Id::to_string).collect();
+        writeln!(f, "Response {} to [{}] differs", self.index, ids.join(", "))?;
+        writeln!(f, "  captured: {}", Printable(&self.captured))?;
+        write!(f, "  replayed: {}", Printable(&self.replayed))
+    }
+}
+
+struct Printable<'a>(&'a Option<Bytes>);
+
+impl fmt::Display for Printable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(bytes) => write!(f, "{}", String::from_utf8_lossy(bytes).escape_debug()),
+            None => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Feeds the requests of a capture to `router` one packet at a time, the way a session would,
+/// and compares what it answers with the captured responses to the same requests. Notifications
+/// pushed while the capture was taken aren't replayed, so they show up as mismatches too
+pub async fn replay<In, R>(path: impl AsRef<Path>, router: Addr<R>) -> Result<Vec<Mismatch>, Error>
+where
+    In: ServerRequest + 'static,
+    In::Result: ServerResponse + Reply + DeserializeOwned,
+    R: Router<In>,
+    R::Context: ToEnvelope<R, Request<In>>,
+{
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    let recording = Recording::open(path)?;
+    let Negotiated {
+        format,
+        framing,
+        compression,
+    } = recording.negotiated;
+
+    let mut decoder = Decoder::<In>::with_framing(format, framing).compressed(compression);
+    let mut encoder = Encoder::<In::Result>::with_framing(format, framing).compressed(compression);
+
+    let mut src = BytesMut::new();
+    for frame in recording.requests() {
+        src.extend_from_slice(&frame.bytes);
+    }
+
+    // Whoever the capture came from isn't recorded, so handlers see no peer
+    let connection = context::next_connection();
+    let request = |message: In| Request {
+        context: RequestContext::new(&message, connection, None, None),
+        message,
+    };
+
+    // Answered the way a session answers them, see `Session::answer`
+    let mut replayed = vec![];
+    while let Some(packet) = decoder.decode_eof(&mut src)? {
+        let batched = matches!(packet, Packet::Batch(_));
+        let requests = packet.into_iter().map(|input| async {
+            let response = match input {
+                Ok(input) => {
+                    let notification = is_notification(&input);
+                    let response = router.send(request(input)).await?;
+                    if notification {
+                        return Ok(vec![]);
+                    }
+
+                    response
+                }
+                Err(DecodeError { id, error }) => {
+                    In::Result::error_response(id.unwrap_or(Id::Null), error)
+                }
+            };
+
+            Ok::<_, Error>(frames(response).await)
+        });
+
+        let mut responses = vec![];
+        for frames in join_all(requests).await {
+            responses.extend(frames?);
+        }
+        let packets = match responses {
+            responses if responses.is_empty() => vec![],
+            responses if batched => vec![Packet::Batch(responses)],
+            responses => responses.into_iter().map(Packet::Single).collect(),
+        };
+
+        for packet in packets {
+            let ids = answered(&packet);
+            let mut dst = BytesMut::new();
+            encoder.encode(packet, &mut dst)?;
+            replayed.push(Some((ids, dst.freeze())));
+        }
+    }
+
+    // Requests worked on at once are answered in whatever order they finish, so responses are
+    // matched by the requests they answer. Frames of the same stream stay in order
+    let mut responses =
+        Decoder::<In::Result>::with_framing(format, framing).compressed(compression);
+    let mut mismatches = vec![];
+    for (index, frame) in recording.responses().enumerate() {
+        let ids = match responses.decode_eof(&mut BytesMut::from(&frame.bytes[..])) {
+            Ok(Some(packet)) => packet
+                .into_iter()
+                .filter_map(|response| response.ok()?.id())
+                .collect(),
+            _ => vec![],
+        };
+
+        let answer = replayed
+            .iter_mut()
+            .find(|answer| matches!(answer, Some((answer_ids, _)) if *answer_ids == ids))
+            .and_then(Option::take);
+        match answer {
+            Some((_, bytes)) if bytes == frame.bytes => {}
+            answer => mismatches.push(Mismatch {
+                index,
+                ids,
+                captured: Some(frame.bytes.clone()),
+                replayed: answer.map(|(_, bytes)| bytes),
+            }),
+        }
+    }
+
+    let unmatched = replayed.into_iter().enumerate();
+    for (index, (ids, bytes)) in unmatched.filter_map(|(i, answer)| Some((i, answer?))) {
+        mismatches.push(Mismatch {
+            index,
+            ids,
+            captured: None,
+            replayed: Some(bytes),
+        });
+    }
+
+    Ok(mismatches)
+}
+
+// The requests a packet answers, leaving out notifications
+fn answered<Out: Reply>(packet: &Packet<Out>) -> Vec<Id> {
+    match packet {
+        Packet::Single(response) => response.id().into_iter().collect(),
+        Packet::Batch(responses) => responses.iter().filter_map(Reply::id).collect(),
+    }
+}
+
+// Streamed answers go out as their frames, one packet each unless they're part of a batch, the
+// way sessions send them
+async fn frames<Out: ServerResponse>(response: Out) -> Vec<Out> {
+    match response.into_stream() {
+        Ok(stream) => stream.frames().map(|(frame, _)| frame).collect().await,
+        Err(response) => vec![response],
+    }
+}
+
+fn max_frame_size(framing: Framing) -> usize {
+    match framing {
+        Framing::Stream => 0,
+        Framing::LengthDelimited { max_frame_size } => max_frame_size,
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(bytes);
+    u32::from_be_bytes(buffer)
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(bytes);
+    u64::from_be_bytes(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::{Decoder as _, Encoder as _, LinesCodec};
+
+    use super::*;
+
+    fn negotiated() -> Negotiated {
+        Negotiated {
+            format: Format::Json,
+            framing: Framing::LengthDelimited {
+                max_frame_size: 1024,
+            },
+            compression: Compression::None,
+        }
+    }
+
+    fn capture_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cliff-{}-{}.cap", name, process::id()))
+    }
+
+    fn bytes(frames: impl Iterator<Item = Frame>) -> Vec<Bytes> {
+        frames.map(|frame| frame.bytes).collect()
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let path = capture_path("round-trip");
+        let capture = Capture::create(&path, Side::Server, negotiated()).unwrap();
+        capture.record(Direction::Inbound, b"request");
+        capture.record(Direction::Outbound, b"response");
+
+        let recording = Recording::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.side, Side::Server);
+        assert_eq!(recording.negotiated.format, Format::Json);
+        assert_eq!(recording.negotiated.framing, negotiated().framing);
+        assert_eq!(recording.negotiated.compression, Compression::None);
+        assert_eq!(bytes(recording.requests().cloned()), vec!["request"]);
+        assert_eq!(bytes(recording.responses().cloned()), vec!["response"]);
+        assert!(recording.frames[0].elapsed <= recording.frames[1].elapsed);
+    }
+
+    #[test]
+    fn client_captures_write_the_requests() {
+        let path = capture_path("client");
+        let capture = Capture::create(&path, Side::Client, negotiated()).unwrap();
+        capture.record(Direction::Outbound, b"request");
+        capture.record(Direction::Inbound, b"response");
+
+        let recording = Recording::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes(recording.requests().cloned()), vec!["request"]);
+        assert_eq!(bytes(recording.responses().cloned()), vec!["response"]);
+    }
+
+    #[test]
+    fn drops_a_frame_cut_short() {
+        let path = capture_path("cut-short");
+        let capture = Capture::create(&path, Side::Server, negotiated()).unwrap();
+        capture.record(Direction::Inbound, b"request");
+        capture.record(Direction::Outbound, b"response");
+        drop(capture);
+
+        let written = fs::read(&path).unwrap();
+        fs::write(&path, &written[..written.len() - 3]).unwrap();
+        let recording = Recording::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes(recording.frames.into_iter()), vec!["request"]);
+    }
+
+    #[test]
+    fn refuses_files_that_arent_captures() {
+        let path = capture_path("not-a-capture");
+        fs::write(&path, [0u8; 64].as_ref()).unwrap();
+
+        let opened = Recording::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(opened.is_err());
+    }
+
+    #[test]
+    fn records_frames_as_the_codec_consumes_them() {
+        let path = capture_path("codec");
+        let capture = Capture::create(&path, Side::Server, negotiated()).unwrap();
+        let mut codec = Captured::new(LinesCodec::new(), Some(capture));
+
+        let mut src = BytesMut::from("fir");
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"st\nsecond\nthi");
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "first");
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "second");
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        let mut dst = BytesMut::new();
+        codec.encode("answer", &mut dst).unwrap();
+
+        let recording = Recording::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            bytes(recording.requests().cloned()),
+            vec!["first\n", "second\n"]
+        );
+        assert_eq!(bytes(recording.responses().cloned()), vec!["answer\n"]);
+    }
+}
@@ -2,19 +2,27 @@ use actix::prelude::*;
 
 use failure::Error;
 
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::{self, StreamExt};
+
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use tokio::io::{ReadHalf, WriteHalf};
-use tokio::net::UnixStream;
 
 use tokio_util::codec::FramedRead;
 
-use super::codec::{Decoder, Encoder, RpcMessage};
+use super::capture::{Capture, Captured};
+use super::codec::{Decoder, Encoder, Packet, RpcMessage};
+use super::handshake::{Negotiated, Protocol};
+use super::transport::Connection;
 
 #[async_trait::async_trait]
 pub trait IpcClient: Actor {
-    async fn connect(path: &str) -> Result<Addr<Self>, Error>;
+    /// Connects to a router served on `address`, see `transport::Address`
+    async fn connect(address: &str) -> Result<Addr<Self>, Error>;
+
+    async fn connect_with(address: &str, protocol: Protocol) -> Result<Addr<Self>, Error>;
 }
 
 pub trait InterfaceMessage: Message + RpcMessage + Serialize + Unpin {}
@@ -25,8 +33,38 @@ impl<M: Message + RpcMessage + Serialize + Unpin> InterfaceMessage for M {}
 #[rtype(result = "()")]
 pub struct InterfaceRequest<I>(pub I);
 
+/// What `client!` actions declared with `stream` resolve to: their items as they arrive, ending
+/// with the router's `End`, or an error
+pub type Streaming<T> = UnboundedReceiver<Result<T, Error>>;
+
+/// Holds every request sent after it until a `FlushBatch`, which writes them as one batch
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BeginBatch;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FlushBatch;
+
+/// Registers an actor to receive the notifications a client listens for
+pub struct Listen<N: Message<Result = ()> + Send>(pub Recipient<N>);
+
+impl<N: Message<Result = ()> + Send> Message for Listen<N> {
+    type Result = ();
+}
+
+/// Sends `msg` to every recipient, forgetting the ones that have stopped
+pub fn broadcast<M>(recipients: &mut Vec<Recipient<M>>, msg: M)
+where
+    M: Message<Result = ()> + Send + Clone,
+{
+    recipients
+        .retain(|recipient| !matches!(recipient.do_send(msg.clone()), Err(SendError::Closed(_))));
+}
+
 pub struct WriteInterface<I: InterfaceMessage> {
-    framed: actix::io::FramedWrite<I, WriteHalf<UnixStream>, Encoder<I>>,
+    framed: actix::io::FramedWrite<Packet<I>, WriteHalf<Connection>, Captured<Encoder<I>>>,
+    batch: Option<Vec<I>>,
 }
 
 impl<I: InterfaceMessage + 'static> Actor for WriteInterface<I> {
@@ -37,16 +75,51 @@ impl<I: InterfaceMessage + 'static> Handler<InterfaceRequest<I>> for WriteInterf
     type Result = ();
 
     fn handle(&mut self, msg: InterfaceRequest<I>, _ctx: &mut Self::Context) -> Self::Result {
-        self.framed.write(msg.0);
+        match self.batch.as_mut() {
+            Some(batch) => batch.push(msg.0),
+            None => self.framed.write(Packet::Single(msg.0)),
+        }
+    }
+}
+
+impl<I: InterfaceMessage + 'static> Handler<BeginBatch> for WriteInterface<I> {
+    type Result = ();
+
+    fn handle(&mut self, _msg: BeginBatch, _ctx: &mut Self::Context) -> Self::Result {
+        self.batch.get_or_insert_with(Vec::new);
+    }
+}
+
+impl<I: InterfaceMessage + 'static> Handler<FlushBatch> for WriteInterface<I> {
+    type Result = ();
+
+    fn handle(&mut self, _msg: FlushBatch, _ctx: &mut Self::Context) -> Self::Result {
+        match self.batch.take() {
+            Some(batch) if !batch.is_empty() => self.framed.write(Packet::Batch(batch)),
+            _ => {}
+        }
     }
 }
 
 impl<I: InterfaceMessage + 'static> actix::io::WriteHandler<Error> for WriteInterface<I> {}
 
 impl<I: InterfaceMessage + 'static> WriteInterface<I> {
-    pub async fn attach(w: WriteHalf<UnixStream>) -> Result<Addr<WriteInterface<I>>, Error> {
+    pub async fn attach(
+        w: WriteHalf<Connection>,
+        negotiated: Negotiated,
+        capture: Option<Capture>,
+    ) -> Result<Addr<WriteInterface<I>>, Error> {
         let addr = Self::create(|ctx| Self {
-            framed: actix::io::FramedWrite::new(w, Encoder::<I>::default(), ctx),
+            framed: actix::io::FramedWrite::new(
+                w,
+                Captured::new(
+                    Encoder::<I>::with_framing(negotiated.format, negotiated.framing)
+                        .compressed(negotiated.compression),
+                    capture,
+                ),
+                ctx,
+            ),
+            batch: None,
         });
 
         Ok(addr)
@@ -58,14 +131,48 @@ pub trait InterfaceResponse: Message + DeserializeOwned + Unpin + Send {}
 impl<M: Message + DeserializeOwned + Unpin + Send> InterfaceResponse for M {}
 
 pub trait Delegate<I: InterfaceResponse>: Actor {
-    fn listen(r: ReadHalf<UnixStream>, ctx: &mut Self::Context);
+    fn listen(
+        r: ReadHalf<Connection>,
+        negotiated: Negotiated,
+        max_message_size: usize,
+        capture: Option<Capture>,
+        ctx: &mut Self::Context,
+    );
 }
 
 impl<I: InterfaceResponse + 'static, D: Actor + StreamHandler<Result<I, Error>>> Delegate<I> for D
 where
     D: Actor<Context = Context<D>>,
 {
-    fn listen(r: ReadHalf<UnixStream>, ctx: &mut Self::Context) {
-        ctx.add_stream(FramedRead::new(r, Decoder::<I>::default()));
+    fn listen(
+        r: ReadHalf<Connection>,
+        negotiated: Negotiated,
+        max_message_size: usize,
+        capture: Option<Capture>,
+        ctx: &mut Self::Context,
+    ) {
+        // Responses are matched by id, so batches can be handled one message at a time
+        let responses = FramedRead::new(
+            r,
+            Captured::new(
+                Decoder::<I>::with_framing(negotiated.format, negotiated.framing)
+                    .compressed(negotiated.compression)
+                    .limited(max_message_size),
+                capture,
+            ),
+        )
+        .flat_map(|packet| {
+            let items: Vec<Result<I, Error>> = match packet {
+                Ok(packet) => packet
+                    .into_iter()
+                    .map(|response| response.map_err(Error::from))
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+
+            stream::iter(items)
+        });
+
+        ctx.add_stream(responses);
     }
 }
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix::Message;
+
+use tracing::{info_span, Span};
+
+use super::auth::Credentials;
+use super::codec::{Id, MethodCall, TraceContext};
+use super::trace::Traced;
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(1);
+
+/// Tells connections served by this process apart in logs
+pub(crate) fn next_connection() -> u64 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What a handler knows about a request besides its fields. `router!` handler blocks see it as
+/// `context`
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    /// Who sent it, when the transport can tell
+    pub peer: Option<Credentials>,
+    /// The connection it came in on, unique within the serving process
+    pub connection: u64,
+    pub id: Id,
+    pub method: &'static str,
+    /// When the request was read off the connection
+    pub received: Instant,
+    /// When the peer stops waiting for an answer, if the server has a timeout
+    pub deadline: Option<Instant>,
+    /// The caller's trace, or a new one when it sent none, with a span of this request's own
+    pub trace: TraceContext,
+    /// The span of the caller's that sent the request
+    pub parent_span: Option<u64>,
+    /// Tags the logs written while the request is handled with its method and trace
+    pub span: Span,
+}
+
+impl RequestContext {
+    pub(crate) fn new<M: MethodCall>(
+        message: &M,
+        connection: u64,
+        peer: Option<Credentials>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let received = Instant::now();
+        let parent = message.trace();
+        let trace = match parent {
+            Some(parent) => parent.child(rand::random()),
+            None => TraceContext {
+                trace_id: rand::random(),
+                span_id: rand::random(),
+            },
+        };
+        let span = info_span!(
+            "request",
+            method = message.method(),
+            connection,
+            trace = %trace
+        );
+
+        RequestContext {
+            peer,
+            connection,
+            id: message.id(),
+            method: message.method(),
+            received,
+            deadline: timeout.map(|timeout| received + timeout),
+            trace,
+            parent_span: parent.map(|parent| parent.span_id),
+            span,
+        }
+    }
+
+    /// `message`, for a generated client to send as part of this request's trace
+    pub fn traced<M>(&self, message: M) -> Traced<M> {
+        Traced {
+            message,
+            trace: self.trace,
+        }
+    }
+
+    /// Whether answering still makes a difference to the peer
+    pub fn expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+}
+
+/// A request on its way to a router, along with its context
+pub struct Request<M> {
+    pub message: M,
+    pub context: RequestContext,
+}
+
+impl<M: Message> Message for Request<M> {
+    type Result = M::Result;
+}
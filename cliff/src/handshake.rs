@@ -0,0 +1,468 @@
+use std::env;
+use std::path::PathBuf;
+
+use failure::{format_err, Error};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use tracing::info;
+
+use super::capture::CAPTURE_DIRECTORY_VAR;
+use super::codec::{Compression, Format, Framing, DEFAULT_MAX_FRAME_SIZE};
+
+// Every connection opens with a hello from the client and a reply from the server:
+// `CLIF` | version | field count | (tag | value count | values...)*
+// The client lists every value it supports for a tag and the server answers with
+// the single value it chose. Unknown tags are ignored, so new fields can be added
+// without breaking peers that don't know about them.
+const MAGIC: &[u8; 4] = b"CLIF";
+const VERSION: u8 = 1;
+
+const FORMAT_TAG: u8 = 0;
+const FRAMING_TAG: u8 = 1;
+const COMPRESSION_TAG: u8 = 2;
+
+type Fields = Vec<(u8, Vec<u8>)>;
+
+/// What one side of a connection is willing to speak, in order of preference
+#[derive(Clone, Debug)]
+pub struct Protocol {
+    pub formats: Vec<Format>,
+    pub framings: Vec<Framing>,
+    pub compressions: Vec<Compression>,
+    /// Directory every connection's frames are captured to, if any. Local to each side
+    pub capture: Option<PathBuf>,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol {
+            formats: Format::available(),
+            framings: vec![Framing::length_delimited(), Framing::Stream],
+            compressions: Compression::available(),
+            capture: env::var_os(CAPTURE_DIRECTORY_VAR).map(PathBuf::from),
+        }
+    }
+}
+
+impl Protocol {
+    pub fn with_format(format: Format) -> Self {
+        Protocol {
+            formats: vec![format],
+            ..Protocol::default()
+        }
+    }
+
+    /// Restricts the connection to a single framing
+    pub fn framed(mut self, framing: Framing) -> Self {
+        self.framings = vec![framing];
+        self
+    }
+
+    /// Prefers a single compression, still falling back to none for peers without it
+    pub fn compressed(mut self, compression: Compression) -> Self {
+        self.compressions = vec![compression, Compression::None];
+        self
+    }
+
+    /// Captures every connection to a file in `directory`, see `capture::Recording`
+    pub fn captured<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.capture = Some(directory.into());
+        self
+    }
+
+    /// The largest frame the length-delimited framing takes, which streamed messages are held to
+    /// as well
+    pub fn max_frame_size(&self) -> usize {
+        self.framings
+            .iter()
+            .find_map(|framing| match framing {
+                Framing::LengthDelimited { max_frame_size } => Some(*max_frame_size),
+                Framing::Stream => None,
+            })
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    fn framing_for(&self, id: u8) -> Option<Framing> {
+        self.framings.iter().copied().find(|f| f.id() == id)
+    }
+
+    // Going without is always an option
+    fn compression_for(&self, id: u8) -> Option<Compression> {
+        if id == Compression::None.id() {
+            return Some(Compression::None);
+        }
+
+        self.compressions.iter().copied().find(|c| c.id() == id)
+    }
+}
+
+/// What both sides agreed on for the lifetime of a connection
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub format: Format,
+    pub framing: Framing,
+    pub compression: Compression,
+}
+
+/// Client side of the handshake
+pub async fn initiate<S>(stream: &mut S, protocol: &Protocol) -> Result<Negotiated, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let offered: Vec<u8> = protocol.formats.iter().map(|f| f.id()).collect();
+    let framings: Vec<u8> = protocol.framings.iter().map(|f| f.id()).collect();
+    let compressions: Vec<u8> = protocol.compressions.iter().map(|c| c.id()).collect();
+    write_fields(
+        stream,
+        &[
+            (FORMAT_TAG, offered.clone()),
+            (FRAMING_TAG, framings),
+            (COMPRESSION_TAG, compressions),
+        ],
+    )
+    .await?;
+
+    let reply = read_fields(stream).await?;
+    let format = match find_field(&reply, FORMAT_TAG) {
+        Some([id]) if offered.contains(id) => Format::from_id(*id),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        format_err!(
+            "Handshake Error: Server accepted none of {:?}",
+            protocol.formats
+        )
+    })?;
+
+    let framing = match find_field(&reply, FRAMING_TAG) {
+        Some([id]) => protocol.framing_for(*id),
+        Some(_) => None,
+        // Servers that predate framing negotiation only know how to stream
+        None => protocol.framing_for(Framing::Stream.id()),
+    }
+    .ok_or_else(|| {
+        format_err!(
+            "Handshake Error: Server accepted none of {:?}",
+            protocol.framings
+        )
+    })?;
+
+    let compression = match find_field(&reply, COMPRESSION_TAG) {
+        Some([id]) => protocol.compression_for(*id),
+        Some(_) => None,
+        // Servers that predate compression negotiation never compress
+        None => Some(Compression::None),
+    }
+    .ok_or_else(|| {
+        format_err!(
+            "Handshake Error: Server picked a compression outside of {:?}",
+            protocol.compressions
+        )
+    })?;
+
+    info!(
+        "Negotiated format: {:?}, framing: {:?}, compression: {:?}",
+        format, framing, compression
+    );
+
+    Ok(Negotiated {
+        format,
+        framing,
+        compression,
+    })
+}
+
+/// Server side of the handshake
+pub async fn accept<S>(stream: &mut S, protocol: &Protocol) -> Result<Negotiated, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = read_fields(stream).await?;
+    answer(stream, protocol, hello).await
+}
+
+/// Server side of the handshake that also takes clients which skip it and send JSON straight
+/// away, speaking streamed JSON with them. Hands back what it read of their first message
+pub async fn accept_or_json<S>(
+    stream: &mut S,
+    protocol: &Protocol,
+) -> Result<(Negotiated, Vec<u8>), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let first = stream.read_u8().await?;
+    if (first == b'{' || first == b'[') && protocol.formats.contains(&Format::Json) {
+        info!("Client skipped the handshake, falling back to streamed JSON");
+
+        let negotiated = Negotiated {
+            format: Format::Json,
+            framing: Framing::Stream,
+            compression: Compression::None,
+        };
+        return Ok((negotiated, vec![first]));
+    }
+
+    let hello = read_fields_after(stream, first).await?;
+    Ok((answer(stream, protocol, hello).await?, vec![]))
+}
+
+async fn answer<S>(stream: &mut S, protocol: &Protocol, hello: Fields) -> Result<Negotiated, Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    // The client's preference wins among the formats we're willing to speak
+    let format = find_field(&hello, FORMAT_TAG)
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|id| Format::from_id(*id))
+        .find(|format| protocol.formats.contains(format));
+
+    let framing = match find_field(&hello, FRAMING_TAG) {
+        Some(ids) => ids.iter().find_map(|id| protocol.framing_for(*id)),
+        // Clients that predate framing negotiation only know how to stream
+        None => protocol.framing_for(Framing::Stream.id()),
+    };
+
+    // Only frames can be flagged as compressed, and clients that don't ask for it don't get it
+    let compression = match (framing, find_field(&hello, COMPRESSION_TAG)) {
+        (Some(Framing::LengthDelimited { .. }), Some(ids)) => ids
+            .iter()
+            .find_map(|id| protocol.compression_for(*id))
+            .unwrap_or(Compression::None),
+        _ => Compression::None,
+    };
+
+    match (format, framing) {
+        (Some(format), Some(framing)) => {
+            write_fields(
+                stream,
+                &[
+                    (FORMAT_TAG, vec![format.id()]),
+                    (FRAMING_TAG, vec![framing.id()]),
+                    (COMPRESSION_TAG, vec![compression.id()]),
+                ],
+            )
+            .await?;
+            info!(
+                "Negotiated format: {:?}, framing: {:?}, compression: {:?}",
+                format, framing, compression
+            );
+
+            Ok(Negotiated {
+                format,
+                framing,
+                compression,
+            })
+        }
+        _ => {
+            write_fields(stream, &[(FORMAT_TAG, vec![]), (FRAMING_TAG, vec![])]).await?;
+
+            Err(format_err!(
+                "Handshake Error: Nothing in common with client, we speak {:?} over {:?}",
+                protocol.formats,
+                protocol.framings
+            ))
+        }
+    }
+}
+
+fn find_field(fields: &[(u8, Vec<u8>)], tag: u8) -> Option<&[u8]> {
+    fields
+        .iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, values)| values.as_slice())
+}
+
+async fn write_fields<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    fields: &[(u8, Vec<u8>)],
+) -> Result<(), Error> {
+    let mut buffer = Vec::with_capacity(6 + fields.len() * 4);
+    buffer.extend_from_slice(MAGIC);
+    buffer.push(VERSION);
+    buffer.push(fields.len() as u8);
+
+    for (tag, values) in fields {
+        buffer.push(*tag);
+        buffer.push(values.len() as u8);
+        buffer.extend_from_slice(values);
+    }
+
+    stream.write_all(&buffer).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+async fn read_fields<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Fields, Error> {
+    let first = stream.read_u8().await?;
+    read_fields_after(stream, first).await
+}
+
+// For when the first byte was already read to tell what the client is speaking
+async fn read_fields_after<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    first: u8,
+) -> Result<Fields, Error> {
+    let mut header = [first, 0, 0, 0, 0, 0];
+    stream.read_exact(&mut header[1..]).await?;
+
+    if &header[..4] != MAGIC {
+        return Err(format_err!(
+            "Handshake Error: Unexpected preamble: {:?}",
+            &header[..4]
+        ));
+    }
+    if header[4] != VERSION {
+        return Err(format_err!(
+            "Handshake Error: Unsupported version: {}",
+            header[4]
+        ));
+    }
+
+    let mut fields = Vec::with_capacity(header[5] as usize);
+    for _ in 0..header[5] {
+        let mut field = [0u8; 2];
+        stream.read_exact(&mut field).await?;
+
+        let mut values = vec![0u8; field[1] as usize];
+        stream.read_exact(&mut values).await?;
+
+        fields.push((field[0], values));
+    }
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::UnixStream;
+
+    fn json() -> Protocol {
+        Protocol::with_format(Format::Json)
+    }
+
+    async fn negotiate(
+        client: &Protocol,
+        server: &Protocol,
+    ) -> (Result<Negotiated, Error>, Result<Negotiated, Error>) {
+        let (mut client_end, mut server_end) = UnixStream::pair().unwrap();
+
+        futures::join!(
+            initiate(&mut client_end, client),
+            accept(&mut server_end, server)
+        )
+    }
+
+    #[tokio::test]
+    async fn both_sides_settle_on_frames() {
+        let (client, server) = negotiate(&json(), &json()).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_eq!(client.format, Format::Json);
+        assert_eq!(client.framing, Framing::length_delimited());
+        assert_eq!(server.framing, client.framing);
+        assert_eq!(server.compression, client.compression);
+    }
+
+    #[tokio::test]
+    async fn streams_when_that_is_all_the_client_knows() {
+        let (client, server) = negotiate(&json().framed(Framing::Stream), &json()).await;
+
+        assert_eq!(client.unwrap().framing, Framing::Stream);
+        assert_eq!(server.unwrap().framing, Framing::Stream);
+    }
+
+    #[tokio::test]
+    async fn fails_on_both_sides_with_nothing_in_common() {
+        let client = json().framed(Framing::Stream);
+        let server = json().framed(Framing::length_delimited());
+        let (client, server) = negotiate(&client, &server).await;
+
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn legacy_clients_stream_uncompressed() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let hello = [(FORMAT_TAG, vec![Format::Json.id()])];
+        write_fields(&mut client, &hello).await.unwrap();
+
+        let negotiated = accept(&mut server, &json()).await.unwrap();
+        assert_eq!(negotiated.framing, Framing::Stream);
+        assert_eq!(negotiated.compression, Compression::None);
+
+        let reply = read_fields(&mut client).await.unwrap();
+        assert_eq!(
+            find_field(&reply, FRAMING_TAG),
+            Some(&[Framing::Stream.id()][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_servers_stream_uncompressed() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let protocol = json();
+        let legacy_server = async {
+            read_fields(&mut server).await.unwrap();
+            let reply = [(FORMAT_TAG, vec![Format::Json.id()])];
+            write_fields(&mut server, &reply).await.unwrap();
+        };
+
+        let (negotiated, _) = futures::join!(initiate(&mut client, &protocol), legacy_server);
+        let negotiated = negotiated.unwrap();
+        assert_eq!(negotiated.framing, Framing::Stream);
+        assert_eq!(negotiated.compression, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn streams_are_never_compressed() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let hello = [
+            (FORMAT_TAG, vec![Format::Json.id()]),
+            (FRAMING_TAG, vec![Framing::Stream.id()]),
+            (COMPRESSION_TAG, vec![2, 1, 0]),
+        ];
+        write_fields(&mut client, &hello).await.unwrap();
+
+        let negotiated = accept(&mut server, &json()).await.unwrap();
+        assert_eq!(negotiated.compression, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_preambles() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        assert!(accept(&mut server, &json()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_skipping_the_handshake_stream_json() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"[{\"jsonrpc\":\"2.0\"}]").await.unwrap();
+
+        let (negotiated, unread) = accept_or_json(&mut server, &json()).await.unwrap();
+        assert_eq!(negotiated.format, Format::Json);
+        assert_eq!(negotiated.framing, Framing::Stream);
+        assert_eq!(unread, b"[");
+    }
+
+    #[tokio::test]
+    async fn clients_with_the_preamble_still_negotiate() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let protocol = json();
+
+        let (initiated, accepted) = futures::join!(
+            initiate(&mut client, &protocol),
+            accept_or_json(&mut server, &protocol)
+        );
+        let (accepted, unread) = accepted.unwrap();
+        assert_eq!(accepted.framing, initiated.unwrap().framing);
+        assert!(unread.is_empty());
+    }
+}
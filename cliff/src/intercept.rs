@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use super::codec::{RpcMessage, RpcMessageType};
+use super::context::{Request, RequestContext};
+use super::server::{ServerRequest, ServerResponse};
+
+/// Cross-cutting behaviour wrapped around every request a router answers. Interceptors meant
+/// for any router implement this for every `In`
+pub trait Interceptor<In: ServerRequest>: 'static
+where
+    In::Result: ServerResponse,
+{
+    /// Sees the request before the router does, and can change it or answer it in its stead
+    fn before(&self, _request: &mut Request<In>) -> Option<In::Result> {
+        None
+    }
+
+    /// Sees whatever answered the request before it goes out, and can replace it. For streamed
+    /// answers that's the frame that ends them
+    fn after(&self, _context: &RequestContext, response: In::Result) -> In::Result {
+        response
+    }
+}
+
+/// The interceptors a session runs requests through. `before` goes in the order they were
+/// added, `after` the other way around, and only for those whose `before` ran
+pub struct Interceptors<In: ServerRequest>
+where
+    In::Result: ServerResponse,
+{
+    chain: Vec<Arc<dyn Interceptor<In>>>,
+}
+
+impl<In: ServerRequest> Clone for Interceptors<In>
+where
+    In::Result: ServerResponse,
+{
+    fn clone(&self) -> Self {
+        Interceptors {
+            chain: self.chain.clone(),
+        }
+    }
+}
+
+impl<In: ServerRequest> Default for Interceptors<In>
+where
+    In::Result: ServerResponse,
+{
+    fn default() -> Self {
+        Interceptors { chain: vec![] }
+    }
+}
+
+impl<In: ServerRequest> Interceptors<In>
+where
+    In::Result: ServerResponse,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<I: Interceptor<In>>(mut self, interceptor: I) -> Self {
+        self.chain.push(Arc::new(interceptor));
+        self
+    }
+
+    /// How many interceptors ran, and the answer if one of them gave it
+    pub(crate) fn before(&self, request: &mut Request<In>) -> (usize, Option<In::Result>) {
+        for (ran, interceptor) in self.chain.iter().enumerate() {
+            if let Some(response) = interceptor.before(request) {
+                return (ran + 1, Some(response));
+            }
+        }
+
+        (self.chain.len(), None)
+    }
+
+    pub(crate) fn after(
+        &self,
+        ran: usize,
+        context: &RequestContext,
+        response: In::Result,
+    ) -> In::Result {
+        self.chain[..ran]
+            .iter()
+            .rev()
+            .fold(response, |response, interceptor| {
+                interceptor.after(context, response)
+            })
+    }
+}
+
+/// Logs every request along with how it went and how long it took
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Logging;
+
+impl<In: ServerRequest> Interceptor<In> for Logging
+where
+    In::Result: ServerResponse,
+{
+    fn after(&self, context: &RequestContext, response: In::Result) -> In::Result {
+        let RequestContext {
+            method,
+            id,
+            connection,
+            received,
+            ..
+        } = context;
+
+        match response.rpc_message_type() {
+            RpcMessageType::Error => warn!(
+                "{} {:?} on connection {} failed after {:?}",
+                method,
+                id,
+                connection,
+                received.elapsed()
+            ),
+            _ => info!(
+                "{} {:?} on connection {} answered in {:?}",
+                method,
+                id,
+                connection,
+                received.elapsed()
+            ),
+        }
+
+        response
+    }
+}
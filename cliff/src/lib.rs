@@ -7,11 +7,22 @@ pub extern crate rand;
 pub extern crate serde;
 pub extern crate tokio;
 
+pub mod auth;
+pub mod capture;
 pub mod client;
+pub mod context;
+pub mod handshake;
+pub mod intercept;
+pub mod metrics;
 pub mod server;
+pub mod trace;
+pub mod transport;
 
 pub mod rpc {
-    pub use super::codec::{RpcMessage, RpcMessageType};
+    pub use super::codec::{
+        Binary, DecodeError, ErrorCode, ErrorData, ErrorResponse, Id, MethodCall, Reply, RpcError,
+        RpcMessage, RpcMessageType, TraceContext,
+    };
 }
 
 pub use macros::*;
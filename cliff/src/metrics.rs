@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+/// Upper bounds of the latency histogram's buckets, in seconds
+const BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+lazy_static! {
+    static ref METHODS: Mutex<BTreeMap<(&'static str, &'static str), MethodMetrics>> =
+        Mutex::new(BTreeMap::new());
+}
+
+#[derive(Default)]
+struct MethodMetrics {
+    requests: u64,
+    errors: u64,
+    /// Cumulative, like Prometheus reports them
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+}
+
+pub(crate) fn record(
+    capability: &'static str,
+    method: &'static str,
+    latency: Duration,
+    failed: bool,
+) {
+    let mut methods = METHODS.lock().unwrap_or_else(|e| e.into_inner());
+    let metrics = methods.entry((capability, method)).or_default();
+
+    let seconds = latency.as_secs_f64();
+    metrics.requests += 1;
+    metrics.seconds += seconds;
+    if failed {
+        metrics.errors += 1;
+    }
+    for (count, bound) in metrics.buckets.iter_mut().zip(BUCKETS.iter()) {
+        if seconds <= *bound {
+            *count += 1;
+        }
+    }
+}
+
+/// Everything this process's routers have answered so far, in Prometheus' text format
+pub fn render() -> String {
+    let methods = METHODS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut out = String::new();
+
+    // Writing to a String can't fail
+    let _ = writeln!(
+        out,
+        "# HELP cliff_requests_total Requests answered by a router"
+    );
+    let _ = writeln!(out, "# TYPE cliff_requests_total counter");
+    for ((capability, method), metrics) in methods.iter() {
+        let _ = writeln!(
+            out,
+            "cliff_requests_total{{{}}} {}",
+            labels(capability, method),
+            metrics.requests
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP cliff_request_errors_total Requests answered with an error"
+    );
+    let _ = writeln!(out, "# TYPE cliff_request_errors_total counter");
+    for ((capability, method), metrics) in methods.iter() {
+        let _ = writeln!(
+            out,
+            "cliff_request_errors_total{{{}}} {}",
+            labels(capability, method),
+            metrics.errors
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP cliff_request_duration_seconds Time from reading a request to answering it"
+    );
+    let _ = writeln!(out, "# TYPE cliff_request_duration_seconds histogram");
+    for ((capability, method), metrics) in methods.iter() {
+        let labels = labels(capability, method);
+
+        for (count, bound) in metrics.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "cliff_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "cliff_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, metrics.requests
+        );
+        let _ = writeln!(
+            out,
+            "cliff_request_duration_seconds_sum{{{}}} {}",
+            labels, metrics.seconds
+        );
+        let _ = writeln!(
+            out,
+            "cliff_request_duration_seconds_count{{{}}} {}",
+            labels, metrics.requests
+        );
+    }
+
+    out
+}
+
+fn labels(capability: &str, method: &str) -> String {
+    format!(
+        "capability=\"{}\",method=\"{}\"",
+        escape(capability),
+        escape(method)
+    )
+}
+
+/// Label values are quoted, so quotes, backslashes and line breaks in them are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(capability: &str) -> Vec<String> {
+        render()
+            .lines()
+            .filter(|line| line.starts_with('#') || line.contains(capability))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn describes_every_metric() {
+        record("Render", "Describe", Duration::from_millis(2), true);
+        let lines = lines("Render");
+
+        for (name, kind) in &[
+            ("cliff_requests_total", "counter"),
+            ("cliff_request_errors_total", "counter"),
+            ("cliff_request_duration_seconds", "histogram"),
+        ] {
+            let help = format!("# HELP {} ", name);
+            let kind = format!("# TYPE {} {}", name, kind);
+            let described = lines
+                .iter()
+                .position(|line| line.starts_with(&help))
+                .unwrap();
+            assert_eq!(lines[described + 1], kind);
+        }
+
+        let labels = "capability=\"Render\",method=\"Describe\"";
+        for sample in &[
+            format!("cliff_requests_total{{{}}} 1", labels),
+            format!("cliff_request_errors_total{{{}}} 1", labels),
+            format!(
+                "cliff_request_duration_seconds_bucket{{{},le=\"0.001\"}} 0",
+                labels
+            ),
+            format!(
+                "cliff_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1",
+                labels
+            ),
+            format!(
+                "cliff_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 1",
+                labels
+            ),
+            format!("cliff_request_duration_seconds_count{{{}}} 1", labels),
+        ] {
+            assert!(lines.contains(sample), "{} in {:#?}", sample, lines);
+        }
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        record(
+            "Escape \"quoted\"",
+            "Back\\slash\nbreak",
+            Duration::from_millis(2),
+            false,
+        );
+
+        let sample = "cliff_requests_total{capability=\"Escape \\\"quoted\\\"\",\
+                      method=\"Back\\\\slash\\nbreak\"} 1";
+        assert!(
+            lines("Escape").iter().any(|line| line == sample),
+            "{}",
+            render()
+        );
+    }
+}
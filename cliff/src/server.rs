@@ -1,40 +1,313 @@
-use std::fs;
-use std::io::ErrorKind;
+use std::fmt;
+use std::io;
 use std::marker::PhantomData;
-use std::os::unix::net;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
 
 use actix::dev::ToEnvelope;
 use actix::prelude::*;
 
-use failure::{Error, ResultExt};
+use failure::{format_err, Error, ResultExt};
+
+use futures::channel::oneshot;
+use futures::future::{self, join_all, AbortHandle, FutureExt, LocalBoxFuture};
+use futures::stream::{self, BoxStream, LocalBoxStream, Stream, StreamExt};
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use tokio::io::WriteHalf;
-use tokio::net::{UnixListener, UnixStream};
-use tokio::stream::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, WriteHalf};
+use tokio::sync::{Notify as Wakeup, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
 
 use tokio_util::codec::FramedRead;
 
-use tracing::{error, info, span, Level};
+use tracing::{debug, error, info, span, warn, Level};
+
+use super::auth::{Credentials, Policy};
+use super::capture::{self, Captured, Side};
+use super::client::broadcast;
+use super::codec::{
+    DecodeError, Decoded, Decoder, Encoder, ErrorCode, ErrorResponse, Id, MethodCall, Packet,
+    RpcError, RpcMessage, RpcMessageType,
+};
+use super::context::{self as request, Request, RequestContext};
+use super::handshake::{accept_or_json, Negotiated, Protocol};
+use super::intercept::Interceptors;
+use super::metrics;
+use super::transport::{Address, Connection, Listener};
+
+pub trait ServerRequest:
+    Message + DeserializeOwned + RpcMessage + MethodCall + Send + Unpin + 'static
+{
+}
+pub trait ServerResponse:
+    Serialize + RpcMessage + ErrorResponse + StreamResponse + Send + Unpin + 'static
+{
+}
+
+impl<M: Message + DeserializeOwned + RpcMessage + MethodCall + Send + Unpin + 'static> ServerRequest
+    for M
+{
+}
+impl<M: Serialize + RpcMessage + ErrorResponse + StreamResponse + Send + Unpin + 'static>
+    ServerResponse for M
+{
+}
+
+/// Responses that can stand in for a stream of them. `router!` implements it for the responses
+/// it declares
+pub trait StreamResponse: Sized {
+    /// The frames to send in place of this response, if it's a stream
+    fn into_stream(self) -> Result<ResponseStream<Self>, Self> {
+        Err(self)
+    }
+}
+
+/// Frames along with whether they're the last one
+type Frames<Out> = BoxStream<'static, (Out, bool)>;
+
+/// What a router answers a request with to stream the result, see `router!`'s `for`. Sessions
+/// send its frames in place of it, as they come
+pub struct ResponseStream<Out> {
+    // Shared so responses stay `Clone`, but only whichever clone is sent first gets the frames
+    frames: Arc<Mutex<Option<Frames<Out>>>>,
+}
+
+impl<Out: ErrorResponse + Send + 'static> ResponseStream<Out> {
+    /// A frame made with `frame` for every item, followed by `end`, or by an error in its place
+    /// if an item is one
+    pub fn new<T: 'static, S, F>(rqs_id: Id, items: S, frame: F, end: Out) -> Self
+    where
+        S: Stream<Item = Result<T, RpcError>> + Send + 'static,
+        F: FnMut(T) -> Out + Send + 'static,
+    {
+        let frames = stream::unfold(
+            (items.boxed(), frame, rqs_id, Some(end)),
+            |(mut items, mut frame, rqs_id, end)| async move {
+                let end = end?;
+
+                match items.next().await {
+                    Some(Ok(item)) => {
+                        Some(((frame(item), false), (items, frame, rqs_id, Some(end))))
+                    }
+                    Some(Err(error)) => {
+                        let error = Out::error_response(rqs_id.clone(), error);
+                        Some(((error, true), (items, frame, rqs_id, None)))
+                    }
+                    None => Some(((end, true), (items, frame, rqs_id, None))),
+                }
+            },
+        );
+
+        ResponseStream {
+            frames: Arc::new(Mutex::new(Some(frames.boxed()))),
+        }
+    }
+
+    pub(crate) fn frames(self) -> Frames<Out> {
+        let frames = self.frames.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+        frames.unwrap_or_else(|| stream::empty().boxed())
+    }
+}
+
+impl<Out> Clone for ResponseStream<Out> {
+    fn clone(&self) -> Self {
+        ResponseStream {
+            frames: self.frames.clone(),
+        }
+    }
+}
+
+impl<Out> fmt::Debug for ResponseStream<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseStream")
+    }
+}
+
+/// Routers answer requests along with their context, see `context::Request`
+pub trait Router<In: ServerRequest>: Actor + Handler<Request<In>> {}
+
+/// Runs a router's handler for the request in `context`, answering with an internal error if it
+/// panics. The router is stopped then, so one started with a `Supervisor` gets restarted instead
+/// of taking every session it serves down with it. `router!` handlers all run through here
+pub fn isolate<C, Out, F>(ctx: &mut C, context: &RequestContext, handle: F) -> Out
+where
+    C: ActorContext,
+    Out: ErrorResponse,
+    F: FnOnce() -> Out,
+{
+    let panic = match panic::catch_unwind(AssertUnwindSafe(handle)) {
+        Ok(response) => return response,
+        Err(panic) => panic,
+    };
+
+    let message = match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown cause".to_string(),
+        },
+    };
+    error!(
+        "{} {} on connection {} panicked, restarting router: {}",
+        context.method, context.id, context.connection, message
+    );
+    ctx.stop();
+
+    Out::error_response(
+        context.id.clone(),
+        RpcError::new(
+            ErrorCode::InternalError,
+            format!("{} panicked", context.method),
+        ),
+    )
+}
+
+/// How long sessions get to answer what they were asked once their server is shut down
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Packets a server session works on at once unless told otherwise
+pub const DEFAULT_CONCURRENCY: usize = 64;
+
+/// Bytes of answers a server session lets pile up for a client before it stops reading, unless
+/// told otherwise
+pub const DEFAULT_HIGH_WATER: usize = 1024 * 1024;
+
+/// How long a server gives a new connection to finish its handshake unless told otherwise
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a server runs, on top of the protocol it negotiates with each connection. None of it is
+/// ever sent to clients
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub protocol: Protocol,
+    /// How many requests a session works on at once before it stops reading more
+    pub concurrency: usize,
+    /// Whether to take over a unix socket another server is still listening on, rather than
+    /// refusing to start
+    pub force: bool,
+    /// Who may call which of the router's methods
+    pub policy: Policy,
+    /// How long the router gets to answer each request before it's answered with an error
+    pub timeout: Option<Duration>,
+    /// How many connections are kept open at once before new ones are turned away
+    pub max_connections: Option<usize>,
+    /// How long a session waits on a client that has nothing in flight before closing it
+    pub idle_timeout: Option<Duration>,
+    /// How many bytes of answers a session lets pile up for a client that isn't reading them
+    /// before it stops reading more packets
+    pub high_water: usize,
+    /// How long a new connection's hello is waited on before it's dropped
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig::new(Protocol::default())
+    }
+}
+
+impl ServerConfig {
+    pub fn new(protocol: Protocol) -> Self {
+        ServerConfig {
+            protocol,
+            concurrency: DEFAULT_CONCURRENCY,
+            force: false,
+            policy: Policy::default(),
+            timeout: None,
+            max_connections: None,
+            idle_timeout: None,
+            high_water: DEFAULT_HIGH_WATER,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Limits how many requests a server session works on at once. Batches count one for each
+    /// of their requests, and ones with more than `limit` go through them `limit` at a time
+    pub fn concurrent(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
+
+    /// Only lets peers call the methods `policy` allows them to. Serving over tcp takes one that
+    /// lets `Anyone` call something, since tcp peers have no credentials
+    pub fn authorized(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Gives up on requests the router hasn't answered within `limit`, see
+    /// `RequestContext::deadline`
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Turns connections away while `max` of them are open
+    pub fn limited(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Closes connections whose client hasn't asked anything within `limit` of its last answer
+    pub fn idle(mut self, limit: Duration) -> Self {
+        self.idle_timeout = Some(limit);
+        self
+    }
 
-use super::codec::{Decoder, Encoder, RpcMessage};
+    /// Stops reading from clients that have `high_water` bytes of answers waiting on them
+    pub fn buffered(mut self, high_water: usize) -> Self {
+        self.high_water = high_water;
+        self
+    }
+
+    /// Drops connections that haven't finished their handshake within `limit`
+    pub fn handshake_within(mut self, limit: Duration) -> Self {
+        self.handshake_timeout = limit;
+        self
+    }
 
-pub trait ServerRequest: Message + DeserializeOwned + RpcMessage + Send + Unpin {}
-pub trait ServerResponse: Serialize + RpcMessage + Send + Unpin {}
+    /// Serves on the address even if a live server already answers there
+    pub fn forced(mut self) -> Self {
+        self.force = true;
+        self
+    }
+}
 
-impl<M: Message + DeserializeOwned + RpcMessage + Send + Unpin> ServerRequest for M {}
-impl<M: Serialize + RpcMessage + Send + Unpin> ServerResponse for M {}
+/// A packet along with the slots it takes up until it's answered
+type Inbound<In> = (Result<Decoded<In>, Error>, Vec<OwnedSemaphorePermit>);
 
-pub trait Router<In: ServerRequest>: Actor + Handler<In> {}
+type SessionWriter<Out> =
+    actix::io::FramedWrite<Packet<Out>, Metered<WriteHalf<Connection>>, Captured<Encoder<Out>>>;
 
 struct Session<In: ServerRequest, R: Router<In>>
 where
     In::Result: ServerResponse,
 {
     router: Addr<R>,
-    client: actix::io::FramedWrite<In::Result, WriteHalf<UnixStream>, Encoder<In::Result>>,
+    client: SessionWriter<In::Result>,
+    reading: SpawnHandle,
+    connection: u64,
+    peer: Option<Credentials>,
+    policy: Policy,
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    concurrency: usize,
+    // Closes the session unless the client asks something first
+    idling: Option<SpawnHandle>,
+    backlog: Arc<Backlog>,
+    interceptors: Interceptors<In>,
+    closed: Option<oneshot::Sender<()>>,
+    // Packets handed to the router and not answered yet
+    in_flight: usize,
+    // Whether the client is done sending
+    finished: bool,
 }
 
 impl<In: ServerRequest + 'static, R: Router<In>> Actor for Session<In, R>
@@ -42,6 +315,45 @@ where
     In::Result: ServerResponse,
 {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.touch(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(closed) = self.closed.take() {
+            let _ = closed.send(());
+        }
+    }
+}
+
+impl<In: ServerRequest + 'static, R: Router<In>> Handler<Notify<In::Result>> for Session<In, R>
+where
+    In::Result: ServerResponse,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Notify<In::Result>, _ctx: &mut Self::Context) -> Self::Result {
+        self.client.write(Packet::Single(msg.0));
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Drain(Duration);
+
+impl<In: ServerRequest + 'static, R: Router<In>> Handler<Drain> for Session<In, R>
+where
+    In::Result: ServerResponse,
+{
+    type Result = ();
+
+    // Winds down, closing at the deadline even if some of what's in flight isn't answered
+    fn handle(&mut self, Drain(deadline): Drain, ctx: &mut Self::Context) -> Self::Result {
+        self.wind_down(ctx);
+
+        ctx.run_later(deadline, |_, ctx| ctx.stop());
+    }
 }
 
 impl<In: ServerRequest + 'static, R: Router<In>> actix::io::WriteHandler<Error> for Session<In, R> where
@@ -49,109 +361,863 @@ impl<In: ServerRequest + 'static, R: Router<In>> actix::io::WriteHandler<Error>
 {
 }
 
-impl<In: ServerRequest + 'static, R: Router<In>> StreamHandler<Result<In, Error>> for Session<In, R>
+impl<In: ServerRequest + 'static, R: Router<In>> StreamHandler<Inbound<In>> for Session<In, R>
 where
     In::Result: ServerResponse,
-    R::Context: ToEnvelope<R, In>,
+    R::Context: ToEnvelope<R, Request<In>>,
 {
-    fn handle(&mut self, msg: Result<In, Error>, ctx: &mut Self::Context) {
+    fn handle(&mut self, (msg, slots): Inbound<In>, ctx: &mut Self::Context) {
         let span = span!(Level::TRACE, "Cliff Server StreamHandler");
         let _enter = span.enter();
 
         match msg {
-            Ok(input) => self
-                .router
-                .send(input)
-                .into_actor(self)
-                .then(|res, act, _| {
-                    match res {
-                        Ok(res) => act.client.write(res),
-                        Err(e) => error!("Error responding to request: {}", e.to_string()),
+            // Requests are answered as soon as they're ready, and each one keeps its slot until
+            // then. Streamed answers go out a frame at a time, as the client keeps up with them,
+            // and keep it until the last one
+            Ok(Packet::Single(Ok(input))) => {
+                self.in_flight += 1;
+
+                let notification = is_notification(&input);
+                let backlog = self.backlog.clone();
+                let frames = self.dispatch(input).then(move |response| {
+                    let backlog = backlog.clone();
+                    async move {
+                        backlog.caught_up().await;
+                        response
+                    }
+                    .boxed_local()
+                });
+
+                actix::fut::wrap_stream::<_, Self>(frames)
+                    .map(move |response, act, _| {
+                        if !notification {
+                            act.client.write(Packet::Single(response))
+                        }
+                    })
+                    .finish()
+                    .then(move |_, act, ctx| {
+                        act.answered(slots, ctx);
+
+                        async {}.into_actor(act)
+                    })
+                    .spawn(ctx)
+            }
+            // The decoder already skipped past the bad message, so the session carries on
+            Ok(Packet::Single(Err(e))) => self.client.write(Packet::Single(rejected(e))),
+            // Batches with more requests than are worked on at once go through them that many
+            // at a time
+            Ok(Packet::Batch(mut inputs)) => {
+                self.in_flight += 1;
+
+                let mut chunks = vec![];
+                while !inputs.is_empty() {
+                    let rest = inputs.split_off(inputs.len().min(self.concurrency));
+                    chunks.push(inputs);
+                    inputs = rest;
+                }
+
+                actix::fut::wrap_stream::<_, Self>(stream::iter(chunks))
+                    .fold(vec![], |mut responses, chunk, act, _| {
+                        let requests: Vec<_> =
+                            chunk.into_iter().map(|input| act.answer(input)).collect();
+
+                        join_all(requests)
+                            .into_actor(act)
+                            .map(move |answers, _, _| {
+                                responses.extend(answers.into_iter().flatten());
+                                responses
+                            })
+                    })
+                    .then(move |responses, act, ctx| {
+                        // Batches of nothing but notifications go unanswered
+                        if !responses.is_empty() {
+                            act.client.write(Packet::Batch(responses));
+                        }
+                        act.answered(slots, ctx);
+
+                        async {}.into_actor(act)
+                    })
+                    .spawn(ctx)
+            }
+            // Anything else leaves the rest of the connection unreadable
+            Err(e) => {
+                error!("Error handling msg: {}", e.to_string());
+                self.wind_down(ctx);
+            }
+        }
+
+        self.touch(ctx);
+    }
+
+    // Whatever was already asked still gets answered before the session goes away
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        self.finished = true;
+
+        if self.in_flight == 0 {
+            self.client.close();
+        }
+    }
+}
+
+impl<In: ServerRequest + 'static, R: Router<In>> Session<In, R>
+where
+    In::Result: ServerResponse,
+    R::Context: ToEnvelope<R, Request<In>>,
+{
+    fn attach(
+        stream: Connection,
+        unread: Vec<u8>,
+        router: Addr<R>,
+        negotiated: Negotiated,
+        config: &ServerConfig,
+        interceptors: Interceptors<In>,
+        closed: Option<oneshot::Sender<()>>,
+    ) -> Addr<Self> {
+        let connection = request::next_connection();
+        let peer = stream.peer_credentials();
+        if let Some(peer) = peer.as_ref() {
+            info!("Connection {} from {:?}", connection, peer);
+        }
+        let concurrency = config.concurrency;
+        let policy = config.policy.clone();
+        let timeout = config.timeout;
+        let max_message_size = config.protocol.max_frame_size();
+        let idle_timeout = config.idle_timeout;
+        let backlog = Arc::new(Backlog::new(config.high_water));
+        let capture = capture::start(&config.protocol, Side::Server, negotiated);
+
+        let session = Session::create(move |ctx| {
+            let (r, w) = tokio::io::split(stream);
+            // Whatever the handshake read past the hello belongs to the first packet
+            let r = io::Cursor::new(unread).chain(r);
+
+            let packets = FramedRead::new(
+                r,
+                Captured::new(
+                    Decoder::<In>::with_framing(negotiated.format, negotiated.framing)
+                        .compressed(negotiated.compression)
+                        .limited(max_message_size),
+                    capture.clone(),
+                ),
+            );
+            let reading = Session::add_stream(in_slots(packets, concurrency, backlog.clone()), ctx);
+            Session {
+                router,
+                client: actix::io::FramedWrite::new(
+                    Metered {
+                        inner: w,
+                        backlog: backlog.clone(),
+                    },
+                    Captured::new(
+                        Encoder::<In::Result>::with_framing(negotiated.format, negotiated.framing)
+                            .compressed(negotiated.compression),
+                        capture,
+                    ),
+                    ctx,
+                ),
+                reading,
+                connection,
+                peer,
+                policy,
+                timeout,
+                idle_timeout,
+                concurrency,
+                idling: None,
+                backlog,
+                interceptors,
+                closed,
+                in_flight: 0,
+                finished: false,
+            }
+        });
+
+        NotificationBroker::<In::Result>::from_registry()
+            .do_send(Subscribe(session.clone().recipient()));
+
+        session
+    }
+
+    // Every request gets an answer, from the router or about why it didn't get to answer, goes
+    // through the interceptors on the way in and out, and is counted once it has its answer.
+    // Streamed answers go through them with the frame that ends them
+    fn dispatch(&self, input: In) -> LocalBoxStream<'static, In::Result> {
+        let capability = input.capability();
+        let mut request = Request {
+            context: RequestContext::new(&input, self.connection, self.peer, self.timeout),
+            message: input,
+        };
+        let span = request.context.span.clone();
+        let _enter = span.enter();
+
+        let (ran, intercepted) = self.interceptors.before(&mut request);
+        let context = request.context.clone();
+
+        let response = match intercepted.or_else(|| self.refuse(&context)) {
+            Some(response) => future::ready(response).boxed_local(),
+            None => self.send(request),
+        };
+
+        let interceptors = self.interceptors.clone();
+        response
+            .map(move |response| {
+                let frames = match response.into_stream() {
+                    Ok(frames) => frames.frames().boxed_local(),
+                    Err(response) => stream::once(future::ready((response, true))).boxed_local(),
+                };
+
+                frames.map(move |(response, last)| {
+                    if !last {
+                        return response;
                     }
-                    async {}.into_actor(act)
+                    let _enter = context.span.enter();
+
+                    let response = interceptors.after(ran, &context, response);
+                    let failed = matches!(response.rpc_message_type(), RpcMessageType::Error);
+                    metrics::record(
+                        capability,
+                        context.method,
+                        context.received.elapsed(),
+                        failed,
+                    );
+
+                    response
                 })
-                .wait(ctx),
-            Err(e) => error!("Error handling msg: {}", e.to_string()),
+            })
+            .flatten_stream()
+            .boxed_local()
+    }
+
+    // Everything a batch answers one of its messages with: each frame of a streamed answer, an
+    // error for one that couldn't be decoded, and nothing for a notification
+    fn answer(&self, input: Result<In, DecodeError>) -> LocalBoxFuture<'static, Vec<In::Result>> {
+        match input {
+            Ok(input) if is_notification(&input) => self
+                .dispatch(input)
+                .collect::<Vec<_>>()
+                .map(|_| vec![])
+                .boxed_local(),
+            Ok(input) => self.dispatch(input).collect().boxed_local(),
+            Err(e) => future::ready(vec![rejected(e)]).boxed_local(),
+        }
+    }
+
+    fn send(&self, request: Request<In>) -> LocalBoxFuture<'static, In::Result> {
+        let (id, method) = (request.context.id.clone(), request.context.method);
+        let span = request.context.span.clone();
+        let mut response = self.router.send(request);
+        if let Some(timeout) = self.timeout {
+            response = response.timeout(timeout);
+        }
+
+        async move {
+            response.await.unwrap_or_else(|e| {
+                let _enter = span.enter();
+                error!("Error responding to {}: {}", method, e);
+
+                In::Result::error_response(
+                    id,
+                    RpcError::new(ErrorCode::InternalError, e.to_string()),
+                )
+            })
+        }
+        .boxed_local()
+    }
+
+    // Requests the peer isn't allowed to make are answered here and never reach the router
+    fn refuse(&self, context: &RequestContext) -> Option<In::Result> {
+        if self.policy.permits(context.method, context.peer.as_ref()) {
+            return None;
+        }
+
+        warn!(
+            "Refusing {} to {:?} on connection {}",
+            context.method, context.peer, context.connection
+        );
+        let error = RpcError::new(
+            ErrorCode::Unauthorized,
+            format!("Not allowed to call {}", context.method),
+        );
+
+        Some(In::Result::error_response(context.id.clone(), error))
+    }
+}
+
+impl<In: ServerRequest + 'static, R: Router<In>> Session<In, R>
+where
+    In::Result: ServerResponse,
+{
+    // The writer stops the session once the last responses are flushed
+    fn answered(&mut self, slots: Vec<OwnedSemaphorePermit>, ctx: &mut Context<Self>) {
+        drop(slots);
+        self.in_flight -= 1;
+
+        if self.finished && self.in_flight == 0 {
+            self.client.close();
+        }
+
+        self.touch(ctx);
+    }
+
+    // Stops reading and closes once everything in flight is answered
+    fn wind_down(&mut self, ctx: &mut Context<Self>) {
+        ctx.cancel_future(self.reading);
+        self.finished = true;
+
+        if self.in_flight == 0 {
+            self.client.close();
+        }
+    }
+
+    // Starts the idle timeout over, for as long as nothing is in flight
+    fn touch(&mut self, ctx: &mut Context<Self>) {
+        if let Some(idling) = self.idling.take() {
+            ctx.cancel_future(idling);
+        }
+
+        let limit = match self.idle_timeout {
+            Some(limit) if self.in_flight == 0 && !self.finished => limit,
+            _ => return,
+        };
+
+        self.idling = Some(ctx.run_later(limit, |act, ctx| {
+            info!("Closing idle connection {}", act.connection);
+            act.wind_down(ctx);
+        }));
+    }
+}
+
+/// Stops reading packets off the connection while `concurrency` requests are being worked on,
+/// or while the client is behind on reading their answers
+fn in_slots<T, S: Stream<Item = Result<Packet<T>, Error>> + Unpin>(
+    packets: S,
+    concurrency: usize,
+    backlog: Arc<Backlog>,
+) -> impl Stream<Item = (S::Item, Vec<OwnedSemaphorePermit>)> {
+    let slots = Arc::new(Semaphore::new(concurrency));
+
+    stream::unfold(
+        (packets, slots, backlog),
+        move |(mut packets, slots, backlog)| async move {
+            let mut taken = vec![slots.clone().acquire_owned().await];
+            backlog.caught_up().await;
+            let packet = packets.next().await?;
+
+            // Batches take a slot for each of their requests, or every slot there is when they
+            // have more requests than that
+            if let Ok(Packet::Batch(inputs)) = &packet {
+                for _ in 1..inputs.len().min(concurrency) {
+                    taken.push(slots.clone().acquire_owned().await);
+                }
+            }
+
+            Some(((packet, taken), (packets, slots, backlog)))
+        },
+    )
+}
+
+pub(crate) fn is_notification<M: RpcMessage>(msg: &M) -> bool {
+    matches!(msg.rpc_message_type(), RpcMessageType::Notification)
+}
+
+// Messages that couldn't be decoded are answered with why, as best as the id can be told
+fn rejected<Out: ErrorResponse>(DecodeError { id, error }: DecodeError) -> Out {
+    warn!("Rejecting malformed message: {}", error);
+
+    Out::error_response(id.unwrap_or(Id::Null), error)
+}
+
+/// Whether the peer closed the connection before its hello was through
+fn hung_up(e: &Error) -> bool {
+    e.downcast_ref::<io::Error>().map(io::Error::kind) == Some(io::ErrorKind::UnexpectedEof)
+}
+
+/// How many bytes a session's writer holds that the connection hasn't taken yet
+struct Backlog {
+    unsent: AtomicUsize,
+    high_water: usize,
+    drained: Wakeup,
+}
+
+impl Backlog {
+    fn new(high_water: usize) -> Self {
+        Backlog {
+            unsent: AtomicUsize::new(0),
+            high_water,
+            drained: Wakeup::new(),
+        }
+    }
+
+    fn set(&self, unsent: usize) {
+        let before = self.unsent.swap(unsent, Ordering::AcqRel);
+
+        if before > self.high_water && unsent <= self.high_water {
+            self.drained.notify();
+        }
+    }
+
+    async fn caught_up(&self) {
+        if self.unsent.load(Ordering::Acquire) <= self.high_water {
+            return;
+        }
+
+        while self.unsent.load(Ordering::Acquire) > self.high_water {
+            self.drained.notified().await;
+        }
+
+        // Only one waiter is woken at a time, so each one wakes the next
+        self.drained.notify();
+    }
+}
+
+/// Keeps a session's `Backlog` up to date with what its writer couldn't write to the connection
+struct Metered<W> {
+    inner: W,
+    backlog: Arc<Backlog>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Metered<W> {
+    // The writer always hands over everything it holds, so whatever isn't written is still unsent
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        let unsent = match &written {
+            Poll::Ready(Ok(n)) => buf.len() - n,
+            _ => buf.len(),
+        };
+        self.backlog.set(unsent);
+
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Pushes a notification declared with `notify` in `router!` to every session serving it
+pub fn notify<N: ServerResponse + Clone + 'static>(notification: N) {
+    match notification.rpc_message_type() {
+        RpcMessageType::Notification => {
+            NotificationBroker::<N>::from_registry().do_send(Notify(notification))
         }
+        _ => error!("Only notifications can be pushed to sessions"),
+    }
+}
+
+struct Notify<N>(N);
+
+impl<N: Clone> Clone for Notify<N> {
+    fn clone(&self) -> Self {
+        Notify(self.0.clone())
+    }
+}
+
+impl<N: Send + 'static> Message for Notify<N> {
+    type Result = ();
+}
+
+struct Subscribe<N: Send + 'static>(Recipient<Notify<N>>);
+
+impl<N: Send + 'static> Message for Subscribe<N> {
+    type Result = ();
+}
+
+/// Keeps track of the sessions a response type's notifications go out to
+struct NotificationBroker<N: Send + 'static> {
+    sessions: Vec<Recipient<Notify<N>>>,
+}
+
+impl<N: Send + 'static> Default for NotificationBroker<N> {
+    fn default() -> Self {
+        Self { sessions: vec![] }
+    }
+}
+
+impl<N: Send + Unpin + 'static> Actor for NotificationBroker<N> {
+    type Context = Context<Self>;
+}
+
+impl<N: Send + Unpin + 'static> Supervised for NotificationBroker<N> {}
+
+impl<N: Send + Unpin + 'static> SystemService for NotificationBroker<N> {}
+
+impl<N: Send + Unpin + 'static> Handler<Subscribe<N>> for NotificationBroker<N> {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe<N>, _ctx: &mut Self::Context) -> Self::Result {
+        self.sessions.push(msg.0);
+    }
+}
+
+impl<N: Clone + Send + Unpin + 'static> Handler<Notify<N>> for NotificationBroker<N> {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notify<N>, _ctx: &mut Self::Context) -> Self::Result {
+        broadcast(&mut self.sessions, msg);
     }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct IpcConnect(pub UnixStream, pub net::SocketAddr);
+pub struct IpcConnect(pub Connection);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Shutdown(Duration);
+
+/// Shuts down a router served with `IpcServer::serve`
+#[derive(Clone)]
+pub struct ServerHandle {
+    address: Address,
+    server: Recipient<Shutdown>,
+}
+
+impl ServerHandle {
+    /// The address clients can reach the router on
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Stops accepting connections and removes the socket file, then gives open sessions until
+    /// `deadline` to answer what they were already asked before closing them
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), Error> {
+        match self.server.send(Shutdown(deadline)).await {
+            // Shut down through another handle already
+            Ok(()) | Err(MailboxError::Closed) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
 
-pub struct IpcServer<In: ServerRequest, R: Router<In>> {
+pub struct IpcServer<In: ServerRequest, R: Router<In>>
+where
+    In::Result: ServerResponse,
+{
     inbound_message: PhantomData<In>,
     router: Addr<R>,
+    config: ServerConfig,
+    interceptors: Interceptors<In>,
+    address: Address,
+    accepting: AbortHandle,
+    sessions: Vec<(Recipient<Drain>, oneshot::Receiver<()>)>,
+    // Connections still going through the handshake
+    negotiating: usize,
+    // Set once shutting down, for connections that were mid-handshake
+    draining: Option<Duration>,
 }
 
-impl<In: ServerRequest + 'static, R: Router<In>> Actor for IpcServer<In, R> {
+impl<In: ServerRequest + 'static, R: Router<In>> Actor for IpcServer<In, R>
+where
+    In::Result: ServerResponse,
+{
     type Context = Context<Self>;
 }
 
 impl<In: ServerRequest + 'static, R: Router<In>> Handler<IpcConnect> for IpcServer<In, R>
 where
     In::Result: ServerResponse,
-    R::Context: ToEnvelope<R, In>,
+    R::Context: ToEnvelope<R, Request<In>>,
 {
     type Result = ();
 
-    fn handle(&mut self, msg: IpcConnect, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: IpcConnect, ctx: &mut Self::Context) -> Self::Result {
+        let IpcConnect(mut stream) = msg;
+
+        // Dropping the connection closes it
+        self.sessions.retain(|(session, _)| session.connected());
+        if let Some(max) = self.config.max_connections {
+            if self.sessions.len() + self.negotiating >= max {
+                warn!(
+                    "Turning away connection to {}, already at {}",
+                    self.address, max
+                );
+                return;
+            }
+        }
+        self.negotiating += 1;
+
         let router = self.router.clone();
-        Session::create(move |ctx| {
-            let (r, w) = tokio::io::split(msg.0);
+        let config = self.config.clone();
+        let interceptors = self.interceptors.clone();
 
-            Session::add_stream(FramedRead::new(r, Decoder::<In>::default()), ctx);
-            Session {
-                router,
-                client: actix::io::FramedWrite::new(w, Encoder::<In::Result>::default(), ctx),
+        ctx.spawn(
+            async move {
+                // Connections count toward the limit while negotiating, so they can't take forever
+                let negotiation = time::timeout(
+                    config.handshake_timeout,
+                    accept_or_json(&mut stream, &config.protocol),
+                );
+                match negotiation.await {
+                    Ok(Ok((negotiated, unread))) => {
+                        let (closed, on_close) = oneshot::channel();
+                        let session = Session::attach(
+                            stream,
+                            unread,
+                            router,
+                            negotiated,
+                            &config,
+                            interceptors,
+                            Some(closed),
+                        );
+
+                        Some((session.recipient(), on_close))
+                    }
+                    // Probes for a live server, like a restart checking its socket, just hang up
+                    Ok(Err(e)) if hung_up(&e) => {
+                        debug!("Peer hung up before negotiating");
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        error!("Error negotiating connection: {}", e.to_string());
+                        None
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Dropping connection that didn't negotiate within {:?}",
+                            config.handshake_timeout
+                        );
+                        None
+                    }
+                }
             }
-        });
+            .into_actor(self)
+            .map(|session, act, _| {
+                act.negotiating -= 1;
+
+                match (session, act.draining) {
+                    (Some((session, _)), Some(deadline)) => {
+                        let _ = session.do_send(Drain(deadline));
+                    }
+                    (Some(session), None) => act.sessions.push(session),
+                    (None, _) => (),
+                }
+            }),
+        );
     }
 }
 
-impl<In: ServerRequest + 'static, R: Router<In>> IpcServer<In, R>
+impl<In: ServerRequest + 'static, R: Router<In>> Handler<Shutdown> for IpcServer<In, R>
 where
     In::Result: ServerResponse,
-    R::Context: ToEnvelope<R, In>,
 {
-    fn new(router: Addr<R>) -> Self {
-        Self {
-            inbound_message: PhantomData,
-            router,
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, Shutdown(deadline): Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        info!("Shutting down {}", self.address);
+
+        self.accepting.abort();
+        self.draining = Some(deadline);
+        if let Err(e) = self.address.remove_socket() {
+            warn!("Couldn't remove socket of {}: {}", self.address, e);
         }
+
+        let closed: Vec<_> = self
+            .sessions
+            .drain(..)
+            .map(|(session, on_close)| {
+                // Sessions that already went away have nothing to drain
+                let _ = session.do_send(Drain(deadline));
+                on_close
+            })
+            .collect();
+
+        // Sessions close themselves by the deadline, so this doesn't need one of its own
+        Box::pin(join_all(closed).into_actor(self).map(|_, act, ctx| {
+            info!("Stopped serving {}", act.address);
+            ctx.stop();
+        }))
+    }
+}
+
+impl<In: ServerRequest + 'static, R: Router<In>> IpcServer<In, R>
+where
+    In::Result: ServerResponse,
+    R::Context: ToEnvelope<R, Request<In>>,
+{
+    /// Serves `router` on `address`, a URI like `unix:///tmp/central.registry` or
+    /// `tcp://0.0.0.0:4000`, until the returned handle shuts it down
+    pub fn serve(address: &str, router: Addr<R>) -> Result<ServerHandle, Error> {
+        Self::serve_with(address, router, ServerConfig::default())
     }
 
-    pub fn serve(path: &str, router: Addr<R>) -> Result<(), Error> {
-        let span = span!(Level::TRACE, "Serving Router", path);
+    pub fn serve_with(
+        address: &str,
+        router: Addr<R>,
+        config: ServerConfig,
+    ) -> Result<ServerHandle, Error> {
+        Self::serve_intercepted(address, router, config, Interceptors::default())
+    }
+
+    /// Serves `router` with every request going through `interceptors` first
+    pub fn serve_intercepted(
+        address: &str,
+        router: Addr<R>,
+        config: ServerConfig,
+        interceptors: Interceptors<In>,
+    ) -> Result<ServerHandle, Error> {
+        let span = span!(Level::TRACE, "Serving Router", address);
         let _enter = span.enter();
 
-        let listener = Box::new(open_uds_listener(path).context("Couldn't open socket")?);
+        let address: Address = address.parse()?;
+        // Tcp peers come without credentials, so a policy that wants them would turn every one away
+        if let Address::Tcp(_) = address {
+            if !config.policy.admits_anonymous() {
+                return Err(format_err!(
+                    "Nobody over tcp could call anything on {}, serve it with a policy that allows Anyone",
+                    address
+                ));
+            }
+        }
+
+        let listener = Listener::bind(&address, config.force).context("Couldn't open listener")?;
+        let address = listener.local_address()?;
+        info!("Serving on {}", address);
 
-        IpcServer::create(move |ctx| {
-            ctx.add_message_stream(Box::leak(listener).incoming().map(|stream| {
-                let stream = stream.unwrap();
-                let addr = stream.peer_addr().unwrap();
-                IpcConnect(stream, addr)
-            }));
-            IpcServer::new(router)
+        // Aborting `stopped` ends the stream of connections
+        let (stopped, accepting) = future::abortable(future::pending::<()>());
+        let incoming = listener.incoming().take_until(stopped);
+        let server = IpcServer::create({
+            let address = address.clone();
+            move |ctx| {
+                ctx.add_message_stream(incoming.filter_map(|connection| {
+                    future::ready(match connection {
+                        Ok(connection) => Some(IpcConnect(connection)),
+                        Err(e) => {
+                            error!("Error accepting connection: {}", e.to_string());
+                            None
+                        }
+                    })
+                }));
+                IpcServer {
+                    inbound_message: PhantomData,
+                    router,
+                    config,
+                    interceptors,
+                    address,
+                    accepting,
+                    sessions: vec![],
+                    negotiating: 0,
+                    draining: None,
+                }
+            }
         });
 
+        Ok(ServerHandle {
+            address,
+            server: server.recipient(),
+        })
+    }
+
+    /// Serves `router` to the process on the other end of stdin and stdout, LSP-style, until it
+    /// closes them. Nothing else may write to stdout in the meantime. That process is taken to
+    /// run as this one's user, see `Connection::peer_credentials`
+    pub async fn serve_stdio(router: Addr<R>) -> Result<(), Error> {
+        Self::serve_stdio_with(router, ServerConfig::default()).await
+    }
+
+    pub async fn serve_stdio_with(router: Addr<R>, config: ServerConfig) -> Result<(), Error> {
+        Self::serve_stdio_intercepted(router, config, Interceptors::default()).await
+    }
+
+    pub async fn serve_stdio_intercepted(
+        router: Addr<R>,
+        config: ServerConfig,
+        interceptors: Interceptors<In>,
+    ) -> Result<(), Error> {
+        let mut stream = Connection::stdio();
+        let (negotiated, unread) = accept_or_json(&mut stream, &config.protocol).await?;
+
+        let (closed, on_close) = oneshot::channel();
+        Session::attach(
+            stream,
+            unread,
+            router,
+            negotiated,
+            &config,
+            interceptors,
+            Some(closed),
+        );
+
+        // Cancelled instead of sent if the session is dropped without stopping, same difference
+        let _ = on_close.await;
+        info!("Stdio connection closed");
+
         Ok(())
     }
 }
 
-fn open_uds_listener(path: &str) -> Result<UnixListener, Error> {
-    match UnixListener::bind(&path) {
-        Ok(l) => Ok(l),
-        Err(e) if e.kind() == ErrorKind::AddrInUse => {
-            // 1. Handle cases where file exists
-            // TODO: Handle it more gracefully (Ask user whether to force or abort)
-            info!("A connection file already exists. Removing it.");
-            fs::remove_file(&path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps every notification it gets
+    struct Subscriber(Arc<Mutex<Vec<u32>>>);
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Notify<u32>> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, msg: Notify<u32>, _ctx: &mut Self::Context) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    /// Stops as soon as it starts, leaving a closed mailbox behind
+    struct Gone;
+
+    impl Actor for Gone {
+        type Context = Context<Self>;
 
-            UnixListener::bind(&path).map_err(Error::from)
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.stop();
         }
-        Err(e) => Err(Error::from(e)),
+    }
+
+    impl Handler<Notify<u32>> for Gone {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Notify<u32>, _ctx: &mut Self::Context) -> Self::Result {}
+    }
+
+    #[test]
+    fn brokers_notifications_to_subscribers_still_around() {
+        System::new("broker").block_on(async {
+            let received = Arc::new(Mutex::new(vec![]));
+            let subscriber = Subscriber(received.clone()).start();
+            let gone = Gone.start();
+            while gone.connected() {
+                time::delay_for(Duration::from_millis(1)).await;
+            }
+
+            let broker = NotificationBroker::<u32>::default().start();
+            broker
+                .send(Subscribe(subscriber.clone().recipient()))
+                .await
+                .unwrap();
+            broker
+                .send(Subscribe(gone.clone().recipient()))
+                .await
+                .unwrap();
+            broker.send(Notify(1)).await.unwrap();
+            broker.send(Notify(2)).await.unwrap();
+
+            // Answered once the notifications ahead of it are handled
+            subscriber.send(Notify(3)).await.unwrap();
+            assert_eq!(*received.lock().unwrap(), vec![1, 2, 3]);
+
+            let mut recipients = vec![subscriber.recipient(), gone.recipient()];
+            broadcast(&mut recipients, Notify(4));
+            assert_eq!(recipients.len(), 1);
+        });
     }
 }
@@ -0,0 +1,79 @@
+use std::cell::Cell;
+
+use actix::Message;
+
+use lazy_static::lazy_static;
+
+use super::codec::TraceContext;
+
+lazy_static! {
+    /// Whatever the process does on its own account, like a cli action, is a trace of its own
+    static ref PROCESS: TraceContext = TraceContext {
+        trace_id: rand::random(),
+        span_id: rand::random(),
+    };
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = Cell::new(None);
+}
+
+/// The trace generated clients stamp requests from this thread with: the one a router handler
+/// on it is answering, or the process's own
+pub fn current() -> TraceContext {
+    CURRENT.with(Cell::get).unwrap_or(*PROCESS)
+}
+
+/// Makes `trace` current until the guard is dropped
+pub fn enter(trace: TraceContext) -> Entered {
+    Entered {
+        previous: CURRENT.with(|current| current.replace(Some(trace))),
+    }
+}
+
+pub struct Entered {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// A message for a generated client to send as part of `trace`. Clients only get to requests
+/// after the handler that sent them returns, so this is how handlers pass their trace on, e.g.
+/// `client.do_send(context.traced(Create { .. }))`
+pub struct Traced<M> {
+    pub message: M,
+    pub trace: TraceContext,
+}
+
+impl<M: Message> Message for Traced<M> {
+    type Result = M::Result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entered_traces_last_until_dropped() {
+        let process = current();
+        let outer = TraceContext {
+            trace_id: 1,
+            span_id: 2,
+        };
+
+        let entered = enter(outer);
+        assert_eq!(current(), outer);
+        {
+            let _inner = enter(outer.child(3));
+            assert_eq!(current(), outer.child(3));
+        }
+        assert_eq!(current(), outer);
+
+        drop(entered);
+        assert_eq!(current(), process);
+    }
+}
@@ -0,0 +1,470 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net as unix;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use failure::{format_err, Error};
+
+use futures::stream::{self, Stream};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use tracing::{info, warn};
+
+use super::auth::Credentials;
+
+const UNIX_SCHEME: &str = "unix://";
+const TCP_SCHEME: &str = "tcp://";
+const SPAWN_SCHEME: &str = "spawn://";
+
+/// Where a router is served, written as a URI: `unix:///tmp/central.registry`,
+/// `tcp://127.0.0.1:4000` or `spawn://note_repo stdio note_command`. Plain paths are taken to
+/// be unix sockets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Unix(PathBuf),
+    /// A `host:port` pair, resolved when connecting
+    Tcp(String),
+    /// A command serving a router on its stdin and stdout, spawned for every connection. It's
+    /// split on whitespace and run without a shell
+    Spawn(String),
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self, Error> {
+        if let Some(path) = address.strip_prefix(UNIX_SCHEME) {
+            Ok(Address::Unix(PathBuf::from(path)))
+        } else if let Some(host) = address.strip_prefix(TCP_SCHEME) {
+            match host.rfind(':') {
+                Some(i) if i > 0 && host[i + 1..].parse::<u16>().is_ok() => {
+                    Ok(Address::Tcp(host.to_string()))
+                }
+                _ => Err(format_err!("Expected host:port in address: {}", address)),
+            }
+        } else if let Some(command) = address.strip_prefix(SPAWN_SCHEME) {
+            match command.trim() {
+                "" => Err(format_err!("Expected a command in address: {}", address)),
+                command => Ok(Address::Spawn(command.to_string())),
+            }
+        } else if address.contains("://") {
+            Err(format_err!("Unsupported transport in address: {}", address))
+        } else {
+            // Addresses registered before they were URIs
+            Ok(Address::Unix(PathBuf::from(address)))
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+            Address::Tcp(host) => write!(f, "{}{}", TCP_SCHEME, host),
+            Address::Spawn(command) => write!(f, "{}{}", SPAWN_SCHEME, command),
+        }
+    }
+}
+
+impl Address {
+    /// Unlinks the socket file a unix listener leaves behind, if it's still there
+    pub(crate) fn remove_socket(&self) -> Result<(), Error> {
+        match self {
+            Address::Unix(path) => match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::from(e)),
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A stream to the other side of a connection, whichever transport it goes over
+pub enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    /// This process's own stdin and stdout, for routers run by another process
+    Stdio(tokio::io::Stdin, tokio::io::Stdout),
+    /// The pipes of a spawned router, which is killed along with the connection
+    Child {
+        process: Child,
+        stdin: ChildStdin,
+        stdout: ChildStdout,
+    },
+}
+
+impl Connection {
+    pub async fn connect(address: &str) -> Result<Self, Error> {
+        match address.parse()? {
+            Address::Unix(path) => Ok(Connection::Unix(UnixStream::connect(path).await?)),
+            Address::Tcp(host) => Connection::tcp(TcpStream::connect(host.as_str()).await?),
+            Address::Spawn(command) => Connection::spawn(&command),
+        }
+    }
+
+    /// Talks over stdin and stdout, so nothing else may write to stdout while it's open
+    pub fn stdio() -> Self {
+        Connection::Stdio(tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    /// Who's on the other end, when the transport can tell
+    pub fn peer_credentials(&self) -> Option<Credentials> {
+        match self {
+            Connection::Unix(stream) => match stream.peer_cred() {
+                Ok(cred) => Some(Credentials {
+                    uid: cred.uid,
+                    gid: cred.gid,
+                    pid: peer_pid(stream),
+                }),
+                Err(e) => {
+                    warn!("Couldn't read peer credentials: {}", e);
+                    None
+                }
+            },
+            // Whoever is on stdio spawned this process, so it's vouched for as running as this
+            // process does, which lets it through `Local` and `Owner`
+            Connection::Stdio(..) => Some(Credentials {
+                pid: Some(unsafe { libc::getppid() }),
+                ..Credentials::own()
+            }),
+            Connection::Tcp(_) | Connection::Child { .. } => None,
+        }
+    }
+
+    fn spawn(command: &str) -> Result<Self, Error> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| format_err!("Nothing to spawn"))?;
+
+        // Stderr is left alone, so whatever the child reports there isn't lost
+        let mut process = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        match (process.stdin.take(), process.stdout.take()) {
+            (Some(stdin), Some(stdout)) => Ok(Connection::Child {
+                process,
+                stdin,
+                stdout,
+            }),
+            _ => Err(format_err!("Couldn't open the pipes of {}", program)),
+        }
+    }
+
+    // Messages are small and answered right away, so they shouldn't wait around to be coalesced
+    fn tcp(stream: TcpStream) -> Result<Self, Error> {
+        stream.set_nodelay(true)?;
+
+        Ok(Connection::Tcp(stream))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Stdio(stdin, _) => Pin::new(stdin).poll_read(cx, buf),
+            Connection::Child { stdout, .. } => Pin::new(stdout).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Stdio(_, stdout) => Pin::new(stdout).poll_write(cx, buf),
+            Connection::Child { stdin, .. } => Pin::new(stdin).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Stdio(_, stdout) => Pin::new(stdout).poll_flush(cx),
+            Connection::Child { stdin, .. } => Pin::new(stdin).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Stdio(_, stdout) => Pin::new(stdout).poll_shutdown(cx),
+            Connection::Child { stdin, .. } => Pin::new(stdin).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Accepts connections on an `Address`
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Refuses to take over a unix socket some other server still answers on, unless `force`d
+    pub fn bind(address: &Address, force: bool) -> Result<Self, Error> {
+        match address {
+            Address::Spawn(_) => Err(format_err!(
+                "Spawned routers serve on stdio rather than listening on {}",
+                address
+            )),
+            Address::Unix(path) => bind_uds(path, force).map(Listener::Unix),
+            Address::Tcp(host) => {
+                let listener = net::TcpListener::bind(host.as_str())?;
+                listener.set_nonblocking(true)?;
+
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            }
+        }
+    }
+
+    /// The address clients can reach, with the actual port when bound to port 0
+    pub fn local_address(&self) -> Result<Address, Error> {
+        match self {
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| Address::Unix(path.to_owned()))
+                .ok_or_else(|| format_err!("Unix listener isn't bound to a path")),
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+        }
+    }
+
+    pub async fn accept(&mut self) -> Result<Connection, Error> {
+        match self {
+            Listener::Unix(listener) => Ok(Connection::Unix(listener.accept().await?.0)),
+            Listener::Tcp(listener) => Connection::tcp(listener.accept().await?.0),
+        }
+    }
+
+    pub fn incoming(self) -> impl Stream<Item = Result<Connection, Error>> {
+        stream::unfold(self, |mut listener| async move {
+            let connection = listener.accept().await;
+
+            Some((connection, listener))
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_pid(stream: &UnixStream) -> Option<i32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match result {
+        0 => Some(cred.pid),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_pid(_stream: &UnixStream) -> Option<i32> {
+    None
+}
+
+/// Where sockets go unless told otherwise: `$XDG_RUNTIME_DIR/central`, or `/tmp/central-<uid>`
+/// where there's no such thing. Only the user running the server can get in
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("central"),
+        None => std::env::temp_dir().join(format!("central-{}", Credentials::own().uid)),
+    }
+}
+
+// Someone else could swap our sockets for theirs in a directory they own or can write to
+fn ensure_private_dir(dir: &Path) -> Result<(), Error> {
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+
+    let metadata = fs::metadata(dir)?;
+    if metadata.uid() != Credentials::own().uid || metadata.mode() & 0o022 != 0 {
+        return Err(format_err!(
+            "{} must belong to and only be writable by the current user",
+            dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// Sockets are only for their owner to connect to, see `auth::Policy` for finer grained rules
+fn bind_uds(path: &Path, force: bool) -> Result<UnixListener, Error> {
+    if path.parent() == Some(runtime_dir().as_path()) {
+        ensure_private_dir(&runtime_dir())?;
+    }
+
+    // Created 0600 rather than narrowed after, so nobody else gets to connect in between. The
+    // umask is the process's, and only other threads creating files at the same time see it
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = bind_unlinked(path, force);
+    unsafe { libc::umask(mask) };
+
+    listener
+}
+
+fn bind_unlinked(path: &Path, force: bool) -> Result<UnixListener, Error> {
+    match UnixListener::bind(path) {
+        Ok(l) => Ok(l),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            if !fs::symlink_metadata(path)?.file_type().is_socket() {
+                return Err(format_err!("{} exists and isn't a socket", path.display()));
+            }
+
+            match is_listening(path) {
+                Ok(true) if !force => {
+                    return Err(format_err!(
+                        "A server is already listening on {}, stop it or force a takeover",
+                        path.display()
+                    ))
+                }
+                Ok(true) => warn!("Taking over {} from a live server", path.display()),
+                // Whoever left it behind is gone
+                Ok(false) => info!("Removing stale socket {}", path.display()),
+                Err(e) => return Err(Error::from(e)),
+            }
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::from(e)),
+                _ => {}
+            }
+
+            UnixListener::bind(path).map_err(Error::from)
+        }
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Whether a server accepts connections on `path`. The probe hangs up without saying anything
+fn is_listening(path: &Path) -> io::Result<bool> {
+    match unix::UnixStream::connect(path) {
+        Ok(_) => Ok(true),
+        // Nobody's accepting on it, or it went away with whoever left it behind
+        Err(e) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound) => {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(address: &str) -> Address {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_unix_addresses() {
+        let socket = Address::Unix(PathBuf::from("/tmp/central.registry"));
+
+        assert_eq!(parse("unix:///tmp/central.registry"), socket);
+        assert_eq!(parse("/tmp/central.registry"), socket);
+        assert_eq!(socket.to_string(), "unix:///tmp/central.registry");
+    }
+
+    #[test]
+    fn parses_tcp_addresses() {
+        assert_eq!(
+            parse("tcp://127.0.0.1:4000"),
+            Address::Tcp("127.0.0.1:4000".to_string())
+        );
+        assert_eq!(
+            parse("tcp://[::1]:4000"),
+            Address::Tcp("[::1]:4000".to_string())
+        );
+        assert_eq!(parse("tcp://localhost:0").to_string(), "tcp://localhost:0");
+    }
+
+    #[test]
+    fn parses_spawn_addresses() {
+        assert_eq!(
+            parse("spawn:// note_repo stdio note_command "),
+            Address::Spawn("note_repo stdio note_command".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for address in &[
+            "tcp://127.0.0.1",
+            "tcp://:4000",
+            "tcp://127.0.0.1:port",
+            "tcp://127.0.0.1:65536",
+            "spawn://",
+            "spawn://   ",
+            "http://127.0.0.1:4000",
+        ] {
+            assert!(address.parse::<Address>().is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn tells_live_sockets_from_stale_ones() {
+        let path = std::env::temp_dir().join(format!("cliff-probe-{}.sock", std::process::id()));
+        assert!(!is_listening(&path).unwrap());
+
+        let listener = unix::UnixListener::bind(&path).unwrap();
+        assert!(is_listening(&path).unwrap());
+
+        drop(listener);
+        let stale = is_listening(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!stale);
+    }
+
+    #[tokio::test]
+    async fn sockets_are_only_open_to_their_owner() {
+        let path =
+            std::env::temp_dir().join(format!("cliff-transport-{}.sock", std::process::id()));
+        let _listener = bind_uds(&path, false).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
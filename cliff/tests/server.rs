@@ -0,0 +1,334 @@
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use cliff::actix::prelude::*;
+use cliff::auth::{Allow, Policy};
+use cliff::context::Request;
+use cliff::futures::stream;
+use cliff::rpc::{ErrorCode, ErrorResponse, Id, MethodCall, RpcError, RpcMessage, RpcMessageType};
+use cliff::serde::{Deserialize, Serialize};
+use cliff::server::{IpcServer, Router, ServerConfig, ServerHandle, StreamResponse};
+use cliff::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use cliff::tokio::net::UnixStream;
+
+pub struct Parrot;
+
+impl Actor for Parrot {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Parrot {}
+
+cliff::router! {
+    Parrot;
+    [
+        Echo [
+            Say { words: String } -> {} => Said [String] { words },
+            Squawk { words: String } -> {
+                if words.is_empty() {
+                    panic!("Nothing to squawk");
+                }
+            } => Said [String] { words },
+            // Knows three lines, and fails when asked for more
+            Recite { lines: u32 } -> {
+                let verses = stream::iter((0..lines).map(|line| match line {
+                    0..=2 => Ok(format!("Verse {}", line)),
+                    _ => Err(RpcError::new(ErrorCode::NotFound, "Forgot the rest")),
+                }));
+            } => for verse in verses => Line [String] { verse },
+        ]
+    ]
+}
+
+/// Counts pokes, which are notifications, and answers with how many it got when asked
+#[derive(Default)]
+pub struct Tally(u32);
+
+impl Actor for Tally {
+    type Context = Context<Self>;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "cliff::serde")]
+pub enum TallyRequest {
+    Poke {},
+    Count { rqs_id: Id },
+}
+
+impl Message for TallyRequest {
+    type Result = TallyResponse;
+}
+
+impl RpcMessage for TallyRequest {
+    fn rpc_message_type(&self) -> RpcMessageType {
+        match self {
+            TallyRequest::Poke {} => RpcMessageType::Notification,
+            TallyRequest::Count { .. } => RpcMessageType::Request,
+        }
+    }
+}
+
+impl MethodCall for TallyRequest {
+    fn method(&self) -> &'static str {
+        match self {
+            TallyRequest::Poke {} => "Poke",
+            TallyRequest::Count { .. } => "Count",
+        }
+    }
+
+    fn id(&self) -> Id {
+        match self {
+            TallyRequest::Poke {} => Id::Null,
+            TallyRequest::Count { rqs_id } => rqs_id.clone(),
+        }
+    }
+
+    fn capability(&self) -> &'static str {
+        "Tally"
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "cliff::serde")]
+pub enum TallyResponse {
+    Counted { rqs_id: Id, pokes: u32 },
+    Error { rqs_id: Id, error: RpcError },
+}
+
+impl RpcMessage for TallyResponse {
+    fn rpc_message_type(&self) -> RpcMessageType {
+        match self {
+            TallyResponse::Counted { .. } => RpcMessageType::Response,
+            TallyResponse::Error { .. } => RpcMessageType::Error,
+        }
+    }
+}
+
+impl ErrorResponse for TallyResponse {
+    fn error_response(rqs_id: Id, error: RpcError) -> Self {
+        TallyResponse::Error { rqs_id, error }
+    }
+}
+
+impl StreamResponse for TallyResponse {}
+
+impl Handler<Request<TallyRequest>> for Tally {
+    type Result = MessageResult<Request<TallyRequest>>;
+
+    fn handle(&mut self, request: Request<TallyRequest>, _ctx: &mut Self::Context) -> Self::Result {
+        let rqs_id = match request.message {
+            TallyRequest::Poke {} => {
+                self.0 += 1;
+                Id::Null
+            }
+            TallyRequest::Count { rqs_id } => rqs_id,
+        };
+
+        MessageResult(TallyResponse::Counted {
+            rqs_id,
+            pokes: self.0,
+        })
+    }
+}
+
+impl Router<TallyRequest> for Tally {}
+
+fn socket() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!(
+        "cliff-test-{}-{}.sock",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    );
+    std::env::temp_dir().join(name)
+}
+
+fn address(path: &Path) -> String {
+    format!("unix://{}", path.display())
+}
+
+fn serve(path: &Path, config: ServerConfig) -> ServerHandle {
+    IpcServer::<EchoRequest, Parrot>::serve_with(&address(path), Parrot.start(), config).unwrap()
+}
+
+fn say(id: u32) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","method":"Say","params":{{"words":"hi"}},"id":{}}}"#,
+        id
+    )
+}
+
+// Sends `request` without a handshake and reads everything the server answers before it hangs up
+async fn exchange(path: &Path, request: &str) -> String {
+    let mut client = UnixStream::connect(path).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut answer = String::new();
+    client.read_to_string(&mut answer).await.unwrap();
+    answer
+}
+
+#[test]
+fn answers_clients_that_skip_the_handshake() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default());
+
+        let answer = exchange(&path, &say(1)).await;
+        assert!(answer.starts_with('{'), "{}", answer);
+        assert!(answer.contains(r#""id":1"#), "{}", answer);
+        assert!(answer.contains(r#""hi""#), "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn refuses_tcp_with_a_policy_no_tcp_peer_passes() {
+    System::new("test").block_on(async {
+        let address = "tcp://127.0.0.1:0";
+        let serve =
+            |config| IpcServer::<EchoRequest, Parrot>::serve_with(address, Parrot.start(), config);
+
+        assert!(serve(ServerConfig::default()).is_err());
+
+        let server = serve(ServerConfig::default().authorized(Policy::new(Allow::Anyone))).unwrap();
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn answers_the_valid_messages_of_a_batch_along_with_the_bad_ones() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default());
+
+        let shout = r#"{"jsonrpc":"2.0","method":"Shout","params":{},"id":2}"#;
+        let answer = exchange(&path, &format!("[{},{},{}]", say(1), shout, say(3))).await;
+        assert!(answer.starts_with('['), "{}", answer);
+        assert_eq!(answer.matches(r#""hi""#).count(), 2, "{}", answer);
+        assert!(answer.contains(r#""code":-32601"#), "{}", answer);
+        for id in &[1, 2, 3] {
+            assert!(answer.contains(&format!(r#""id":{}"#, id)), "{}", answer);
+        }
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn answers_an_empty_batch_with_a_single_error() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default());
+
+        let answer = exchange(&path, "[]").await;
+        assert!(answer.starts_with('{'), "{}", answer);
+        assert!(answer.contains(r#""code":-32600"#), "{}", answer);
+        assert!(answer.contains(r#""id":null"#), "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn queues_batches_bigger_than_the_concurrency_limit() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default().concurrent(2));
+
+        let batch: Vec<_> = (1..=5).map(say).collect();
+        let answer = exchange(&path, &format!("[{}]", batch.join(","))).await;
+        assert!(answer.starts_with('['), "{}", answer);
+        assert_eq!(answer.matches(r#""hi""#).count(), 5, "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn leaves_batches_of_notifications_unanswered() {
+    System::new("test").block_on(async {
+        let path = socket();
+        // One packet at a time, so the count waits on the pokes
+        let config = ServerConfig::default().concurrent(1);
+        let tally = Tally::default().start();
+        let server =
+            IpcServer::<TallyRequest, Tally>::serve_with(&address(&path), tally, config).unwrap();
+
+        let poke = r#"{"jsonrpc":"2.0","method":"Poke"}"#;
+        let count = r#"{"jsonrpc":"2.0","method":"Count","id":1}"#;
+        let answer = exchange(&path, &format!("[{},{}]{}", poke, poke, count)).await;
+        assert!(answer.starts_with('{'), "{}", answer);
+        assert!(answer.contains(r#""pokes":2"#), "{}", answer);
+        assert_eq!(answer.matches("jsonrpc").count(), 1, "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn answers_panicking_handlers_with_an_error_and_keeps_serving() {
+    System::new("test").block_on(async {
+        let path = socket();
+        // One packet at a time, so the router is restarted before it's asked again
+        let config = ServerConfig::default().concurrent(1);
+        let parrot = Supervisor::start(|_| Parrot);
+        let server =
+            IpcServer::<EchoRequest, Parrot>::serve_with(&address(&path), parrot, config).unwrap();
+
+        let squawk = r#"{"jsonrpc":"2.0","method":"Squawk","params":{"words":""},"id":1}"#;
+        let answer = exchange(&path, &format!("{}{}", squawk, say(2))).await;
+        assert!(answer.contains(r#""code":-32603"#), "{}", answer);
+        assert!(answer.contains(r#""id":1"#), "{}", answer);
+        assert!(answer.contains(r#""hi""#), "{}", answer);
+        assert!(answer.contains(r#""id":2"#), "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+fn recite(lines: u32) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","method":"Recite","params":{{"lines":{}}},"id":1}}"#,
+        lines
+    )
+}
+
+#[test]
+fn streams_a_frame_for_each_item_and_then_an_end() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default());
+
+        let answer = exchange(&path, &recite(3)).await;
+        let frames = ["Verse 0", "Verse 1", "Verse 2", r#""End""#];
+        let at: Vec<_> = frames.iter().map(|frame| answer.find(frame)).collect();
+        assert!(at.iter().all(Option::is_some), "{}", answer);
+        assert!(at.windows(2).all(|pair| pair[0] < pair[1]), "{}", answer);
+        assert_eq!(answer.matches("jsonrpc").count(), 4, "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
+
+#[test]
+fn ends_streams_with_an_error_in_place_of_the_end() {
+    System::new("test").block_on(async {
+        let path = socket();
+        let server = serve(&path, ServerConfig::default());
+
+        let answer = exchange(&path, &recite(5)).await;
+        let verses = answer.find("Verse 2").unwrap();
+        let error = answer.find(r#""code":-32001"#).unwrap();
+        assert!(verses < error, "{}", answer);
+        assert!(!answer.contains(r#""End""#), "{}", answer);
+        assert_eq!(answer.matches("jsonrpc").count(), 4, "{}", answer);
+
+        server.shutdown(Duration::from_secs(1)).await.unwrap();
+    });
+}
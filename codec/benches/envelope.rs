@@ -0,0 +1,121 @@
+use bytes::BytesMut;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use serde::{Deserialize, Serialize};
+
+use serde_json as json;
+
+use tokio_util::codec::{Decoder as _, Encoder as _};
+
+use codec::{Decoder, Encoder, Format, Id, Packet, RpcError, RpcMessage, RpcMessageType};
+
+const MIB: usize = 1024 * 1024;
+
+// Shaped like the note repository's answer to `GetContent`
+#[derive(Serialize, Deserialize, Debug)]
+enum NoteResponse {
+    Content { rqs_id: Id, content: String },
+    Error { rqs_id: Id, error: RpcError },
+}
+
+impl RpcMessage for NoteResponse {
+    fn rpc_message_type(&self) -> RpcMessageType {
+        match self {
+            NoteResponse::Content { .. } => RpcMessageType::Response,
+            NoteResponse::Error { .. } => RpcMessageType::Error,
+        }
+    }
+}
+
+fn content(size: usize) -> NoteResponse {
+    let line = "# Heading\n\nSome \"quoted\" note text, with a tab\tand more words.\n";
+
+    NoteResponse::Content {
+        rqs_id: Id::from(7u32),
+        content: line.repeat(size / line.len()),
+    }
+}
+
+fn encoded(size: usize) -> BytesMut {
+    let mut dst = BytesMut::new();
+    Encoder::new(Format::Json)
+        .encode(Packet::Single(content(size)), &mut dst)
+        .unwrap();
+
+    dst
+}
+
+// What encoding and decoding looked like when every message went through `json::Value`
+fn encode_through_value(msg: &NoteResponse, dst: &mut BytesMut) {
+    let value = json::to_value(msg).unwrap();
+    let id = value["Content"]["rqs_id"].clone();
+    let envelope = json::json!({ "jsonrpc": "2.0", "result": value, "id": id });
+
+    dst.extend_from_slice(&json::to_vec(&envelope).unwrap());
+}
+
+fn decode_through_value(src: &[u8]) -> NoteResponse {
+    let value: json::Value = json::from_slice(src).unwrap();
+
+    json::from_value(value["result"].clone()).unwrap()
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode GetContent");
+
+    for size in [MIB, 2 * MIB, 4 * MIB].iter() {
+        let msg = content(*size);
+        group.throughput(Throughput::Bytes(encoded(*size).len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("envelope", size), size, |b, size| {
+            let mut encoder = Encoder::new(Format::Json);
+            b.iter_batched(
+                || content(*size),
+                |msg| {
+                    let mut dst = BytesMut::new();
+                    encoder.encode(Packet::Single(msg), &mut dst).unwrap();
+                    dst
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("value", size), &msg, |b, msg| {
+            b.iter(|| {
+                let mut dst = BytesMut::new();
+                encode_through_value(msg, &mut dst);
+                dst
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode GetContent");
+
+    for size in [MIB, 2 * MIB, 4 * MIB].iter() {
+        let src = encoded(*size);
+        group.throughput(Throughput::Bytes(src.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("envelope", size), &src, |b, src| {
+            let mut decoder = Decoder::<NoteResponse>::new(Format::Json);
+            b.iter_batched(
+                || src.clone(),
+                |mut src| decoder.decode(&mut src).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("value", size), &src, |b, src| {
+            b.iter(|| decode_through_value(src))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Raw bytes for message fields. CBOR carries them as byte strings, while JSON and msgpack,
+/// whose serializer calls itself human readable, get a base64 string
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Binary(pub Vec<u8>);
+
+impl Binary {
+    pub fn new() -> Self {
+        Binary(Vec::new())
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl fmt::Debug for Binary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Binary({} bytes)", self.0.len())
+    }
+}
+
+impl Deref for Binary {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for Binary {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for Binary {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Binary {
+    fn from(bytes: Vec<u8>) -> Self {
+        Binary(bytes)
+    }
+}
+
+impl From<&[u8]> for Binary {
+    fn from(bytes: &[u8]) -> Self {
+        Binary(bytes.to_vec())
+    }
+}
+
+impl From<Binary> for Vec<u8> {
+    fn from(binary: Binary) -> Self {
+        binary.0
+    }
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(&base64::display::Base64Display::with_config(
+                &self.0,
+                base64::STANDARD,
+            ))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+// Whatever the format says about itself, both representations are accepted so peers that
+// disagree on it still understand each other
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BinaryVisitor)
+    }
+}
+
+struct BinaryVisitor;
+
+impl<'de> Visitor<'de> for BinaryVisitor {
+    type Value = Binary;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bytes or a base64 string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Binary, E> {
+        base64::decode(v)
+            .map(Binary)
+            .map_err(|e| E::custom(format!("invalid base64: {}", e)))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Binary, E> {
+        Ok(Binary(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Binary, E> {
+        Ok(Binary(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Binary, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+
+        Ok(Binary(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes() -> Binary {
+        Binary::from(&b"\x00\xffhello"[..])
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn round_trips_through_json_as_base64() {
+        let json = serde_json::to_string(&bytes()).unwrap();
+
+        assert_eq!(json, r#""AP9oZWxsbw==""#);
+        assert_eq!(serde_json::from_str::<Binary>(&json).unwrap(), bytes());
+        // Peers that send plain arrays are understood too
+        assert_eq!(
+            serde_json::from_str::<Binary>("[0,255,104,101,108,108,111]").unwrap(),
+            bytes()
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn round_trips_through_msgpack() {
+        let packed = rmp_serde::to_vec(&bytes()).unwrap();
+        assert_eq!(
+            rmp_serde::from_read_ref::<_, Binary>(&packed).unwrap(),
+            bytes()
+        );
+
+        // Peers that send `bin` are understood too
+        let mut bin = vec![0xc4, 7];
+        bin.extend_from_slice(&bytes());
+        assert_eq!(
+            rmp_serde::from_read_ref::<_, Binary>(&bin).unwrap(),
+            bytes()
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn round_trips_through_cbor_as_a_byte_string() {
+        let packed = serde_cbor::to_vec(&bytes()).unwrap();
+
+        assert_eq!(packed[0], 0x47);
+        assert_eq!(serde_cbor::from_slice::<Binary>(&packed).unwrap(), bytes());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn rejects_truncated_bytes() {
+        let packed = rmp_serde::to_vec(&bytes()).unwrap();
+
+        assert!(rmp_serde::from_read_ref::<_, Binary>(&packed[..packed.len() - 1]).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn rejects_truncated_base64() {
+        assert!(serde_json::from_str::<Binary>(r#""AP9oZWxsb""#).is_err());
+    }
+}
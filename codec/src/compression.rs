@@ -0,0 +1,251 @@
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+use failure::format_err;
+use failure::Error;
+
+/// Frames smaller than this go out as they are unless told otherwise (16 KiB)
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+#[cfg(feature = "zstd")]
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// How large frames are compressed. Only length-delimited frames can say whether they were,
+/// so streamed connections never compress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4 { threshold: usize },
+    #[cfg(feature = "zstd")]
+    Zstd { threshold: usize, level: i32 },
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Every compression compiled into this build, in order of preference
+    pub fn available() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Compression::zstd(),
+            #[cfg(feature = "lz4")]
+            Compression::lz4(),
+            Compression::None,
+        ]
+    }
+
+    #[cfg(feature = "lz4")]
+    pub fn lz4() -> Self {
+        Compression::Lz4 {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn zstd() -> Self {
+        Compression::Zstd {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+
+    /// Identifies the compression during the handshake. Thresholds and levels are local to each
+    /// side, since only the sender needs them
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 { .. } => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => 2,
+        }
+    }
+
+    /// The threshold and level, zero where they don't apply
+    pub fn settings(self) -> (usize, i32) {
+        match self {
+            Compression::None => (0, 0),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 { threshold } => (threshold, 0),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { threshold, level } => (threshold, level),
+        }
+    }
+
+    /// Undoes `id` and `settings`, as long as this build has the compression
+    #[allow(unused_variables)]
+    pub fn from_settings(id: u8, threshold: usize, level: i32) -> Option<Self> {
+        let compression = Compression::available()
+            .into_iter()
+            .find(|c| c.id() == id)?;
+
+        Some(match compression {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 { .. } => Compression::Lz4 { threshold },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => Compression::Zstd { threshold, level },
+            other => other,
+        })
+    }
+
+    pub(crate) fn is_enabled(self) -> bool {
+        self != Compression::None
+    }
+
+    /// Compresses frames past the threshold, as long as that actually makes them smaller
+    #[allow(unused_variables)]
+    pub(crate) fn compress(self, frame: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 { threshold } if frame.len() >= threshold => {
+                Ok(smaller(lz4_flex::compress_prepend_size(frame), frame))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { threshold, level } if frame.len() >= threshold => {
+                Ok(smaller(zstd::stream::encode_all(frame, level)?, frame))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Fails rather than inflate past `limit`, the same limit the frame had to fit on the wire
+    #[allow(unused_variables)]
+    pub(crate) fn decompress(self, frame: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(frame.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 { .. } => {
+                let size = match frame {
+                    [a, b, c, d, ..] => u32::from_le_bytes([*a, *b, *c, *d]) as usize,
+                    _ => return Err(format_err!("Compressed frame is missing its size")),
+                };
+                if size > limit {
+                    return Err(too_large(limit));
+                }
+
+                lz4_flex::decompress_size_prepended(frame)
+                    .map_err(|e| format_err!("Failed to decompress frame: {:?}", e))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => {
+                use std::io::Read;
+
+                let mut decompressed = vec![];
+                zstd::stream::read::Decoder::new(frame)?
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > limit {
+                    return Err(too_large(limit));
+                }
+
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn smaller(compressed: Vec<u8>, frame: &[u8]) -> Option<Vec<u8>> {
+    Some(compressed).filter(|compressed| compressed.len() < frame.len())
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn too_large(limit: usize) -> Error {
+    format_err!("Frame decompresses past the {} byte limit", limit)
+}
+
+#[cfg(all(test, any(feature = "lz4", feature = "zstd")))]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::Framing;
+
+    fn available() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "lz4")]
+            Compression::lz4(),
+            #[cfg(feature = "zstd")]
+            Compression::zstd(),
+        ]
+    }
+
+    fn repetitive(len: usize) -> Vec<u8> {
+        b"{\"jsonrpc\":\"2.0\",\"result\":null}"
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let frame = repetitive(2 * DEFAULT_COMPRESSION_THRESHOLD);
+
+        for compression in available() {
+            let compressed = compression.compress(&frame).unwrap().unwrap();
+            assert!(compressed.len() < frame.len());
+
+            let decompressed = compression.decompress(&compressed, frame.len()).unwrap();
+            assert_eq!(decompressed, frame, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn leaves_small_frames_alone() {
+        let frame = repetitive(DEFAULT_COMPRESSION_THRESHOLD - 1);
+
+        for compression in available() {
+            assert_eq!(compression.compress(&frame).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn stops_inflating_past_the_limit() {
+        let frame = repetitive(2 * DEFAULT_COMPRESSION_THRESHOLD);
+
+        for compression in available() {
+            let compressed = compression.compress(&frame).unwrap().unwrap();
+            assert!(compression
+                .decompress(&compressed, frame.len() - 1)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_frames_that_inflate_past_the_frame_limit() {
+        let frame = repetitive(2 * DEFAULT_COMPRESSION_THRESHOLD);
+        let limit = DEFAULT_COMPRESSION_THRESHOLD;
+
+        for compression in available() {
+            let mut sender = Framing::length_delimited().frames().unwrap();
+            sender.compression = compression;
+            let mut src = BytesMut::new();
+            sender.encode(&frame, &mut src).unwrap();
+            sender.encode(b"next", &mut src).unwrap();
+
+            let mut receiver = Framing::LengthDelimited {
+                max_frame_size: limit,
+            }
+            .frames()
+            .unwrap();
+            receiver.compression = compression;
+            assert!(receiver.decode(&mut src).is_err());
+            assert_eq!(&receiver.decode(&mut src).unwrap().unwrap()[..], b"next");
+        }
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        for compression in available() {
+            let (threshold, level) = compression.settings();
+            let restored = Compression::from_settings(compression.id(), threshold, level);
+
+            assert_eq!(restored, Some(compression));
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+
+use failure::Fail;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Id;
+
+/// Name of the response variant every router reserves for error objects
+pub(crate) const ERROR_VARIANT: &str = "Error";
+
+/// JSON-RPC 2.0 error codes, followed by the application range our providers use
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    // Application errors (-32000 to -32099 is reserved for implementations)
+    StoreFailure,
+    NotFound,
+    InvalidUuid,
+    Unauthorized,
+    Other(i64),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> i64 {
+        use ErrorCode::*;
+
+        match self {
+            ParseError => -32700,
+            InvalidRequest => -32600,
+            MethodNotFound => -32601,
+            InvalidParams => -32602,
+            InternalError => -32603,
+            StoreFailure => -32000,
+            NotFound => -32001,
+            InvalidUuid => -32002,
+            Unauthorized => -32003,
+            Other(code) => code,
+        }
+    }
+
+    pub fn from_code(code: i64) -> Self {
+        use ErrorCode::*;
+
+        match code {
+            -32700 => ParseError,
+            -32600 => InvalidRequest,
+            -32601 => MethodNotFound,
+            -32602 => InvalidParams,
+            -32603 => InternalError,
+            -32000 => StoreFailure,
+            -32001 => NotFound,
+            -32002 => InvalidUuid,
+            -32003 => Unauthorized,
+            code => Other(code),
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ErrorCode::from_code(i64::deserialize(deserializer)?))
+    }
+}
+
+/// Structured context carried in the `data` member of an error object
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+}
+
+/// A JSON-RPC 2.0 error object: `{ code, message, data }`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ErrorData>,
+}
+
+impl RpcError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Builds an error from a `failure::Error`, keeping its cause chain as `data`
+    pub fn from_failure(code: ErrorCode, error: &failure::Error) -> Self {
+        let causes: Vec<String> = error.iter_chain().skip(1).map(|c| c.to_string()).collect();
+
+        RpcError {
+            code,
+            message: error.to_string(),
+            data: if causes.is_empty() {
+                None
+            } else {
+                Some(ErrorData {
+                    method: None,
+                    causes,
+                })
+            },
+        }
+    }
+
+    pub fn with_method(mut self, method: impl Into<String>) -> Self {
+        self.data.get_or_insert_with(ErrorData::default).method = Some(method.into());
+        self
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code.code())
+    }
+}
+
+impl Fail for RpcError {}
+
+/// A message that couldn't be decoded, with the id it carried when that much was readable
+#[derive(Clone, Debug)]
+pub struct DecodeError {
+    pub id: Option<Id>,
+    pub error: RpcError,
+}
+
+impl DecodeError {
+    pub fn new(id: Option<Id>, error: RpcError) -> Self {
+        DecodeError { id, error }
+    }
+}
+
+impl From<RpcError> for DecodeError {
+    fn from(error: RpcError) -> Self {
+        DecodeError { id: None, error }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.id {
+            Some(id) => write!(f, "{} (id: {})", self.error, id),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl Fail for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use failure::{format_err, ResultExt};
+
+    use super::*;
+
+    #[test]
+    fn maps_codes_both_ways() {
+        use ErrorCode::*;
+
+        for code in &[
+            ParseError,
+            InvalidRequest,
+            MethodNotFound,
+            InvalidParams,
+            InternalError,
+            StoreFailure,
+            NotFound,
+            InvalidUuid,
+            Unauthorized,
+            Other(-32099),
+            Other(42),
+        ] {
+            assert_eq!(ErrorCode::from_code(code.code()), *code);
+        }
+        assert_eq!(MethodNotFound.code(), -32601);
+        assert_eq!(Unauthorized.code(), -32003);
+    }
+
+    #[test]
+    fn keeps_the_cause_chain_as_data() {
+        let error: failure::Error = Err::<(), _>(format_err!("Disk full"))
+            .context("Couldn't save note")
+            .unwrap_err()
+            .into();
+        let error = RpcError::from_failure(ErrorCode::StoreFailure, &error).with_method("Create");
+
+        assert_eq!(error.message, "Couldn't save note");
+        assert_eq!(
+            error.data,
+            Some(ErrorData {
+                method: Some("Create".to_string()),
+                causes: vec!["Disk full".to_string()],
+            })
+        );
+        assert_eq!(error.to_string(), "Couldn't save note (-32000)");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn serializes_as_an_error_object() {
+        let error = RpcError::new(ErrorCode::NotFound, "Note not found");
+        let json = serde_json::to_value(&error).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({ "code": -32001, "message": "Note not found" })
+        );
+        assert_eq!(serde_json::from_value::<RpcError>(json).unwrap(), error);
+    }
+}
@@ -0,0 +1,230 @@
+use std::cmp;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use failure::{format_err, Error};
+
+use crate::{Compression, DecodeError, ErrorCode, RpcError};
+
+// With compression on, every frame starts with one of these
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Largest frame a length-delimited decoder accepts unless told otherwise (8 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// How message boundaries are found on the wire
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Messages are written back to back and the payload format finds where each one ends
+    Stream,
+    /// Every packet is prefixed with its length as a big-endian `u32`
+    LengthDelimited { max_frame_size: usize },
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::length_delimited()
+    }
+}
+
+impl Framing {
+    pub fn length_delimited() -> Self {
+        Framing::LengthDelimited {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Identifies the framing during the handshake. The frame size limit is local to each side
+    pub fn id(self) -> u8 {
+        match self {
+            Framing::Stream => 0,
+            Framing::LengthDelimited { .. } => 1,
+        }
+    }
+
+    pub(crate) fn frames(self) -> Option<Frames> {
+        match self {
+            Framing::Stream => None,
+            Framing::LengthDelimited { max_frame_size } => Some(Frames {
+                max_frame_size,
+                discarding: 0,
+                compression: Compression::None,
+            }),
+        }
+    }
+}
+
+/// Splits length-prefixed frames off a buffer, skipping over the ones that are too large
+pub(crate) struct Frames {
+    max_frame_size: usize,
+    discarding: usize,
+    pub compression: Compression,
+}
+
+impl Frames {
+    pub fn encode(&self, frame: &[u8], dst: &mut BytesMut) -> Result<(), Error> {
+        if !self.compression.is_enabled() {
+            return write_frame(None, frame, dst);
+        }
+
+        match self.compression.compress(frame)? {
+            Some(compressed) => write_frame(Some(COMPRESSED), &compressed, dst),
+            None => write_frame(Some(RAW), frame, dst),
+        }
+    }
+
+    pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        if !self.discard(src) || src.len() < 4 {
+            return Ok(None);
+        }
+
+        let size = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if size > self.max_frame_size {
+            // The frame is dropped as it arrives so the next one can still be read
+            self.discarding = 4 + size;
+            self.discard(src);
+
+            return Err(malformed_frame(&format!(
+                "Frame of {} bytes exceeds the {} byte limit",
+                size, self.max_frame_size
+            )));
+        }
+
+        if src.len() < 4 + size {
+            src.reserve(4 + size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let frame = src.split_to(size);
+        if !self.compression.is_enabled() {
+            return Ok(Some(frame));
+        }
+
+        self.uncompress(frame).map(Some)
+    }
+
+    fn uncompress(&self, mut frame: BytesMut) -> Result<BytesMut, Error> {
+        let flag = match frame.first() {
+            Some(flag) => *flag,
+            None => return Err(malformed_frame("Frame is missing its compression flag")),
+        };
+        frame.advance(1);
+
+        match flag {
+            RAW => Ok(frame),
+            COMPRESSED => self
+                .compression
+                .decompress(&frame, self.max_frame_size)
+                .map(|decompressed| BytesMut::from(&decompressed[..]))
+                .map_err(|e| malformed_frame(&e.to_string())),
+            flag => Err(malformed_frame(&format!(
+                "Unknown compression flag: {}",
+                flag
+            ))),
+        }
+    }
+
+    // Returns whether the oversized frame being skipped is now behind us
+    fn discard(&mut self, src: &mut BytesMut) -> bool {
+        let skipped = cmp::min(self.discarding, src.len());
+        src.advance(skipped);
+        self.discarding -= skipped;
+
+        self.discarding == 0
+    }
+}
+
+fn write_frame(flag: Option<u8>, payload: &[u8], dst: &mut BytesMut) -> Result<(), Error> {
+    let size = payload.len() + flag.is_some() as usize;
+    if size > u32::MAX as usize {
+        return Err(format_err!(
+            "Encoding Error: Frame of {} bytes can't be length prefixed",
+            size
+        ));
+    }
+
+    dst.reserve(4 + size);
+    dst.put_u32(size as u32);
+    if let Some(flag) = flag {
+        dst.put_u8(flag);
+    }
+    dst.extend_from_slice(payload);
+
+    Ok(())
+}
+
+fn malformed_frame(message: &str) -> Error {
+    DecodeError::from(RpcError::new(ErrorCode::InvalidRequest, message)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(max_frame_size: usize) -> Frames {
+        Framing::LengthDelimited { max_frame_size }
+            .frames()
+            .unwrap()
+    }
+
+    fn framed(payloads: &[&[u8]]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        for payload in payloads {
+            write_frame(None, payload, &mut buffer).unwrap();
+        }
+
+        buffer
+    }
+
+    fn rejection(error: Error) -> ErrorCode {
+        error.downcast::<DecodeError>().unwrap().error.code
+    }
+
+    #[test]
+    fn waits_for_whole_frames() {
+        let mut frames = frames(DEFAULT_MAX_FRAME_SIZE);
+        let whole = framed(&[b"hello"]);
+
+        let mut src = BytesMut::from(&whole[..6]);
+        assert_eq!(frames.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&whole[6..]);
+        assert_eq!(&frames.decode(&mut src).unwrap().unwrap()[..], b"hello");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn rejects_frames_past_the_limit() {
+        let mut frames = frames(8);
+        let mut src = framed(&[b"more than eight bytes", b"next"]);
+
+        let error = frames.decode(&mut src).unwrap_err();
+        assert_eq!(rejection(error), ErrorCode::InvalidRequest);
+        assert_eq!(&frames.decode(&mut src).unwrap().unwrap()[..], b"next");
+    }
+
+    #[test]
+    fn resyncs_after_oversized_frames_that_arrive_in_pieces() {
+        let mut frames = frames(8);
+        let wire = framed(&[b"more than eight bytes", b"next"]);
+        let (first, rest) = wire.split_at(10);
+
+        let mut src = BytesMut::from(first);
+        assert!(frames.decode(&mut src).is_err());
+        assert!(src.is_empty());
+
+        src.extend_from_slice(rest);
+        assert_eq!(&frames.decode(&mut src).unwrap().unwrap()[..], b"next");
+        assert_eq!(frames.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn takes_frames_right_at_the_limit() {
+        let mut frames = frames(8);
+        let mut src = framed(&[b"12345678"]);
+
+        assert_eq!(&frames.decode(&mut src).unwrap().unwrap()[..], b"12345678");
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A request id as JSON-RPC 2.0 allows it: a number, a string or null
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(i64),
+    String(String),
+    Null,
+}
+
+impl From<u32> for Id {
+    fn from(id: u32) -> Self {
+        Id::Number(id.into())
+    }
+}
+
+impl From<i64> for Id {
+    fn from(id: i64) -> Self {
+        Id::Number(id)
+    }
+}
+
+impl From<String> for Id {
+    fn from(id: String) -> Self {
+        Id::String(id)
+    }
+}
+
+impl From<&str> for Id {
+    fn from(id: &str) -> Self {
+        Id::String(id.to_string())
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Id::Number(id) => write!(f, "{}", id),
+            Id::String(id) => write!(f, "{:?}", id),
+            Id::Null => write!(f, "null"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_kind_of_id() {
+        for (id, json) in [
+            (Id::from(7u32), "7"),
+            (Id::from(-1i64), "-1"),
+            (Id::from("abc"), "\"abc\""),
+            (Id::Null, "null"),
+        ] {
+            assert_eq!(serde_json::to_string(&id).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Id>(json).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn displays_strings_quoted() {
+        assert_eq!(Id::from(7u32).to_string(), "7");
+        assert_eq!(Id::from("7").to_string(), "\"7\"");
+        assert_eq!(Id::Null.to_string(), "null");
+    }
+}
@@ -1,6 +1,18 @@
+mod binary;
+mod compression;
+mod error;
+mod framing;
+mod id;
 mod rpc;
+mod trace;
 
-pub use rpc::{Encoder, Decoder};
+pub use binary::Binary;
+pub use compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+pub use error::{DecodeError, ErrorCode, ErrorData, RpcError};
+pub use framing::{Framing, DEFAULT_MAX_FRAME_SIZE};
+pub use id::Id;
+pub use rpc::{Decoder, Encoder, Format};
+pub use trace::TraceContext;
 
 pub enum RpcMessageType {
     Request,
@@ -13,6 +25,53 @@ pub trait RpcMessage {
     fn rpc_message_type(&self) -> RpcMessageType;
 }
 
+/// Responses that can carry an error object back to whoever sent `id`
+pub trait ErrorResponse {
+    fn error_response(id: Id, error: RpcError) -> Self;
+}
+
+/// Responses that can tell which request they answer. Notifications answer none
+pub trait Reply {
+    fn id(&self) -> Option<Id>;
+}
+
+/// Requests that can tell which method they call before they're handled
+pub trait MethodCall {
+    fn method(&self) -> &'static str;
+    fn id(&self) -> Id;
+
+    /// The capability the method belongs to, e.g. `NoteQuery` for `NoteQueryRequest`
+    fn capability(&self) -> &'static str;
+
+    /// The caller's trace, for requests that carry one
+    fn trace(&self) -> Option<TraceContext> {
+        None
+    }
+}
+
+/// What crosses the wire in one go: a single message or a JSON-RPC batch of them
+#[derive(Debug)]
+pub enum Packet<T> {
+    Single(T),
+    Batch(Vec<T>),
+}
+
+/// A packet as it was read, with each of its messages or why it couldn't be decoded. Bad
+/// messages are skipped over, so they can be answered on their own while the rest carry on
+pub type Decoded<T> = Packet<Result<T, DecodeError>>;
+
+impl<T> IntoIterator for Packet<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Packet::Single(msg) => vec![msg].into_iter(),
+            Packet::Batch(msgs) => msgs.into_iter(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,199 @@
+//! The msgpack-rpc envelope, shared by the binary formats: `[0, msgid, method, params]` for
+//! requests, `[1, msgid, error, result]` for responses and `[2, method, params]` for
+//! notifications, with params in declaration order.
+use std::fmt;
+use std::marker::PhantomData;
+
+use failure::{format_err, Error};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserializer, Serialize, Serializer};
+
+use tracing::info;
+
+use crate::error::ERROR_VARIANT;
+use crate::rpc::envelope::{self, EnvelopeSeed, ErrorBody, Field, Params};
+use crate::{DecodeError, ErrorCode, Id, RpcError, RpcMessage, RpcMessageType};
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const NOTIFICATION: u8 = 2;
+
+pub fn encode_message<Out, S>(msg: &Out, serializer: S) -> Result<(), Error>
+where
+    Out: Serialize + RpcMessage,
+    S: Serializer,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let probe = envelope::probe(msg).map_err(|e| format_err!("Encoding Error: {}", e))?;
+    let skipped = probe.has_id as usize;
+    info!("Encoding: {}", probe.variant);
+
+    let id = Field::new(msg, envelope::ID_FIELD);
+
+    match msg.rpc_message_type() {
+        RpcMessageType::Request => {
+            (REQUEST, id, probe.variant, Params::positional(msg, skipped)).serialize(serializer)?
+        }
+        RpcMessageType::Notification => (
+            NOTIFICATION,
+            probe.variant,
+            Params::positional(msg, skipped),
+        )
+            .serialize(serializer)?,
+        RpcMessageType::Response => (RESPONSE, id, (), msg).serialize(serializer)?,
+        RpcMessageType::Error => {
+            (RESPONSE, id, Field::new(msg, envelope::ERROR_FIELD), ()).serialize(serializer)?
+        }
+    };
+
+    Ok(())
+}
+
+/// The parts of the envelope that were read before decoding failed, if it did
+#[derive(Default)]
+struct Header {
+    kind: Option<u8>,
+    id: Option<Id>,
+}
+
+pub fn decode_message<'de, In, D>(deserializer: D) -> Result<In, DecodeError>
+where
+    In: DeserializeOwned,
+    D: Deserializer<'de>,
+{
+    let mut header = Header::default();
+    let parsed = deserializer.deserialize_seq(MessageVisitor {
+        header: &mut header,
+        message: PhantomData,
+    });
+
+    parsed.map_err(|e| decode_error(header, e.to_string()))
+}
+
+/// Classifies a failed decode, keeping the msgid when the envelope had one
+fn decode_error(header: Header, message: String) -> DecodeError {
+    let code = match header.kind {
+        Some(REQUEST) | Some(NOTIFICATION) if message.contains("unknown variant") => {
+            ErrorCode::MethodNotFound
+        }
+        Some(REQUEST) | Some(NOTIFICATION) => ErrorCode::InvalidParams,
+        _ => ErrorCode::InvalidRequest,
+    };
+
+    DecodeError::new(header.id, RpcError::new(code, message))
+}
+
+struct MessageVisitor<'h, In> {
+    header: &'h mut Header,
+    message: PhantomData<In>,
+}
+
+impl<'de, 'h, In: DeserializeOwned> Visitor<'de> for MessageVisitor<'h, In> {
+    type Value = In;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a msgpack-rpc message")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<In, A::Error> {
+        let kind: u8 = next(&mut seq)?;
+        self.header.kind = Some(kind);
+
+        let msg = match kind {
+            REQUEST => {
+                let id: Id = next(&mut seq)?;
+                self.header.id = Some(id.clone());
+                let method: String = next(&mut seq)?;
+
+                next_seed(&mut seq, EnvelopeSeed::new(&method, Some(id)))?
+            }
+            RESPONSE => {
+                let id: Id = next(&mut seq)?;
+                self.header.id = Some(id.clone());
+
+                match next_seed(&mut seq, ErrorSlot::new(id))? {
+                    Some(error) => {
+                        let _: IgnoredAny = next(&mut seq)?;
+                        error
+                    }
+                    None => next(&mut seq)?,
+                }
+            }
+            NOTIFICATION => {
+                let method: String = next(&mut seq)?;
+
+                next_seed(&mut seq, EnvelopeSeed::new(&method, None))?
+            }
+            kind => {
+                return Err(de::Error::custom(format!(
+                    "Unexpected Message type: {}",
+                    kind
+                )))
+            }
+        };
+
+        if seq.next_element::<IgnoredAny>()?.is_some() {
+            return Err(de::Error::custom("Unexpected trailing values in message"));
+        }
+
+        Ok(msg)
+    }
+}
+
+fn next<'de, A: SeqAccess<'de>, T: de::Deserialize<'de>>(seq: &mut A) -> Result<T, A::Error> {
+    next_seed(seq, PhantomData)
+}
+
+fn next_seed<'de, A: SeqAccess<'de>, S: DeserializeSeed<'de>>(
+    seq: &mut A,
+    seed: S,
+) -> Result<S::Value, A::Error> {
+    seq.next_element_seed(seed)?
+        .ok_or_else(|| de::Error::custom("Message is missing values"))
+}
+
+/// Reads the error slot of a response, which is nil unless the request failed
+struct ErrorSlot<In> {
+    id: Id,
+    message: PhantomData<In>,
+}
+
+impl<In> ErrorSlot<In> {
+    fn new(id: Id) -> Self {
+        ErrorSlot {
+            id,
+            message: PhantomData,
+        }
+    }
+}
+
+impl<'de, In: DeserializeOwned> DeserializeSeed<'de> for ErrorSlot<In> {
+    type Value = Option<In>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<In>, D::Error> {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de, In: DeserializeOwned> Visitor<'de> for ErrorSlot<In> {
+    type Value = Option<In>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an error object or nil")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<In>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<In>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<In>, D::Error> {
+        EnvelopeSeed::new(ERROR_VARIANT, Some(self.id))
+            .deserialize(ErrorBody::new(deserializer))
+            .map(Some)
+    }
+}
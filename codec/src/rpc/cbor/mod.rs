@@ -0,0 +1,153 @@
+mod parsing;
+
+use std::marker::PhantomData;
+
+use bytes::BytesMut;
+
+use failure::Error;
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::Serialize;
+
+use serde_cbor as cbor;
+
+use tracing::info;
+
+use crate::{DecodeError, Decoded, ErrorCode, Packet, RpcError, RpcMessage};
+
+use parsing::*;
+
+pub struct Encoder<Out> {
+    outbound_message: PhantomData<Out>,
+}
+
+impl<Out> Default for Encoder<Out> {
+    fn default() -> Self {
+        Self {
+            outbound_message: PhantomData,
+        }
+    }
+}
+
+impl<Out: Serialize + RpcMessage> tokio_util::codec::Encoder<Out> for Encoder<Out> {
+    type Error = Error;
+
+    fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), Error> {
+        encode_message(&msg, dst)
+    }
+}
+
+pub struct Decoder<In> {
+    inbound_message: PhantomData<In>,
+}
+
+impl<In> Default for Decoder<In> {
+    fn default() -> Self {
+        Self {
+            inbound_message: PhantomData,
+        }
+    }
+}
+
+impl<In: DeserializeOwned> tokio_util::codec::Decoder for Decoder<In> {
+    type Item = Decoded<In>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        // Skipping over the value first tells where it ends, whether or not it decodes
+        let size = {
+            let mut values = cbor::Deserializer::from_slice(&src[..]).into_iter::<IgnoredAny>();
+            match values.next() {
+                Some(Ok(_)) => values.byte_offset(),
+                Some(Err(e)) if e.is_eof() => return Ok(None),
+                Some(Err(e)) => {
+                    // There's no telling where a malformed value ends, so what's buffered goes
+                    src.clear();
+                    return Err(DecodeError::from(RpcError::new(
+                        ErrorCode::ParseError,
+                        e.to_string(),
+                    ))
+                    .into());
+                }
+                None => return Ok(None),
+            }
+        };
+        let to_parse = src.split_to(size);
+        info!("Decoding: {} bytes", to_parse.len());
+        // The value was split off already, so a bad one doesn't hold up the next
+        let parsed = parse_decoded(&to_parse)?;
+
+        Ok(Some(Packet::Single(Ok(parsed))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    use crate::{Id, RpcMessageType};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Say { rqs_id: Id, words: String },
+        Poke { times: u32 },
+        Error { rqs_id: Id, error: RpcError },
+    }
+
+    impl RpcMessage for Message {
+        fn rpc_message_type(&self) -> RpcMessageType {
+            match self {
+                Message::Say { .. } => RpcMessageType::Request,
+                Message::Poke { .. } => RpcMessageType::Notification,
+                Message::Error { .. } => RpcMessageType::Error,
+            }
+        }
+    }
+
+    fn round_trip(message: Message) -> Message {
+        let mut buffer = BytesMut::new();
+        Encoder::default().encode(message, &mut buffer).unwrap();
+
+        let decoded = Decoder::default().decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        match decoded {
+            Packet::Single(Ok(message)) => message,
+            packet => panic!("Expected a single message, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn round_trips_requests() {
+        let say = || Message::Say {
+            rqs_id: Id::from(7u32),
+            words: "hi".to_string(),
+        };
+
+        assert_eq!(round_trip(say()), say());
+    }
+
+    #[test]
+    fn round_trips_notifications() {
+        assert_eq!(
+            round_trip(Message::Poke { times: 3 }),
+            Message::Poke { times: 3 }
+        );
+    }
+
+    #[test]
+    fn round_trips_error_envelopes() {
+        let error = || Message::Error {
+            rqs_id: Id::from(7u32),
+            error: RpcError::new(ErrorCode::NotFound, "Note not found"),
+        };
+
+        assert_eq!(round_trip(error()), error());
+    }
+}
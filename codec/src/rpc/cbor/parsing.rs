@@ -0,0 +1,24 @@
+use bytes::{buf::*, BytesMut};
+
+use failure::Error;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use serde_cbor as cbor;
+
+use crate::rpc::array;
+use crate::{DecodeError, RpcMessage};
+
+pub fn encode_message<Out: Serialize + RpcMessage>(
+    msg: &Out,
+    dst: &mut BytesMut,
+) -> Result<(), Error> {
+    let mut serializer = cbor::Serializer::new(cbor::ser::IoWrite::new(dst.writer()));
+
+    array::encode_message(msg, &mut serializer)
+}
+
+pub fn parse_decoded<In: DeserializeOwned>(bytes: &[u8]) -> Result<In, DecodeError> {
+    array::decode_message(&mut cbor::Deserializer::from_slice(bytes))
+}
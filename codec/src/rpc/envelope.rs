@@ -0,0 +1,860 @@
+//! Serde adapters that move messages in and out of the RPC envelopes without building an
+//! intermediate `Value`.
+//!
+//! Router messages are externally tagged enums of struct variants, `Variant { rqs_id, .. }`.
+//! On the way out the variant becomes the method, `rqs_id` the id and the rest the params.
+//! On the way in the variant and id are injected back while the params stream through.
+//! Requests may also carry a `trace`, which JSON-RPC lifts into the envelope next to the id,
+//! and the fixed slots of the array envelopes leave as their last positional param.
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Impossible, Serialize, SerializeStructVariant, Serializer};
+
+use crate::{Id, TraceContext};
+
+pub const ID_FIELD: &str = "rqs_id";
+pub const ERROR_FIELD: &str = "error";
+pub const TRACE_FIELD: &str = "trace";
+
+const NOT_A_VARIANT: &str = "Expected a message with struct variants";
+
+// Every adapter below only makes sense for a struct variant at the top level
+macro_rules! reject_non_variants {
+    ($error:ty) => {
+        type SerializeSeq = Impossible<Self::Ok, $error>;
+        type SerializeTuple = Impossible<Self::Ok, $error>;
+        type SerializeTupleStruct = Impossible<Self::Ok, $error>;
+        type SerializeTupleVariant = Impossible<Self::Ok, $error>;
+        type SerializeMap = Impossible<Self::Ok, $error>;
+        type SerializeStruct = Impossible<Self::Ok, $error>;
+
+        reject_non_variants!(@scalar $error;
+            serialize_bool: bool, serialize_i8: i8, serialize_i16: i16, serialize_i32: i32,
+            serialize_i64: i64, serialize_u8: u8, serialize_u16: u16, serialize_u32: u32,
+            serialize_u64: u64, serialize_f32: f32, serialize_f64: f64, serialize_char: char,
+            serialize_str: &str, serialize_bytes: &[u8], serialize_unit_struct: &'static str
+        );
+
+        fn serialize_none(self) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_unit(self) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_unit_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+        ) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            _: &T,
+        ) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: &T,
+        ) -> Result<Self::Ok, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_tuple_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleStruct, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_tuple_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleVariant, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+
+        fn serialize_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeStruct, $error> {
+            Err(ser::Error::custom(NOT_A_VARIANT))
+        }
+    };
+    (@scalar $error:ty; $($method:ident: $ty:ty),*) => {
+        $(
+            fn $method(self, _: $ty) -> Result<Self::Ok, $error> {
+                Err(ser::Error::custom(NOT_A_VARIANT))
+            }
+        )*
+    };
+}
+
+/// What the envelope needs to know about a message before writing it
+pub struct Probe {
+    pub variant: &'static str,
+    pub has_id: bool,
+    pub has_trace: bool,
+}
+
+pub fn probe<M: Serialize>(msg: &M) -> Result<Probe, de::value::Error> {
+    msg.serialize(ProbeSerializer)
+}
+
+struct ProbeSerializer;
+
+impl Serializer for ProbeSerializer {
+    type Ok = Probe;
+    type Error = de::value::Error;
+    type SerializeStructVariant = ProbeFields;
+
+    reject_non_variants!(de::value::Error);
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<ProbeFields, Self::Error> {
+        Ok(ProbeFields(Probe {
+            variant,
+            has_id: false,
+            has_trace: false,
+        }))
+    }
+}
+
+struct ProbeFields(Probe);
+
+impl SerializeStructVariant for ProbeFields {
+    type Ok = Probe;
+    type Error = de::value::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        _: &T,
+    ) -> Result<(), Self::Error> {
+        // Only the field names matter, so values are never visited
+        match key {
+            ID_FIELD => self.0.has_id = true,
+            TRACE_FIELD => self.0.has_trace = true,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Probe, Self::Error> {
+        Ok(self.0)
+    }
+}
+
+/// Serializes a message's fields, minus its id, as a map (JSON-RPC, which also leaves out the
+/// trace) or a sequence (msgpack-rpc and the other array envelopes)
+pub struct Params<'a, M> {
+    msg: &'a M,
+    positional: Option<usize>,
+}
+
+impl<'a, M> Params<'a, M> {
+    #[cfg(feature = "json")]
+    pub fn named(msg: &'a M) -> Self {
+        Params {
+            msg,
+            positional: None,
+        }
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    /// `skipped` is how many of the variant's fields are left out (1 when it carries an id)
+    pub fn positional(msg: &'a M, skipped: usize) -> Self {
+        Params {
+            msg,
+            positional: Some(skipped),
+        }
+    }
+}
+
+impl<'a, M: Serialize> Serialize for Params<'a, M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.msg.serialize(ParamsSerializer {
+            inner: serializer,
+            positional: self.positional,
+        })
+    }
+}
+
+struct ParamsSerializer<S> {
+    inner: S,
+    positional: Option<usize>,
+}
+
+impl<S: Serializer> Serializer for ParamsSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeStructVariant = ParamsFields<S>;
+
+    reject_non_variants!(S::Error);
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        len: usize,
+    ) -> Result<ParamsFields<S>, S::Error> {
+        match self.positional {
+            Some(skipped) => Ok(ParamsFields::Positional(
+                self.inner.serialize_tuple(len.saturating_sub(skipped))?,
+            )),
+            None => Ok(ParamsFields::Named(self.inner.serialize_map(None)?)),
+        }
+    }
+}
+
+enum ParamsFields<S: Serializer> {
+    Named(S::SerializeMap),
+    Positional(S::SerializeTuple),
+}
+
+impl<S: Serializer> SerializeStructVariant for ParamsFields<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), S::Error> {
+        use ser::{SerializeMap, SerializeTuple};
+
+        match (self, key) {
+            (_, ID_FIELD) | (ParamsFields::Named(_), TRACE_FIELD) => Ok(()),
+            (ParamsFields::Named(map), _) => map.serialize_entry(key, value),
+            (ParamsFields::Positional(tuple), _) => tuple.serialize_element(value),
+        }
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeTuple};
+
+        match self {
+            ParamsFields::Named(map) => map.end(),
+            ParamsFields::Positional(tuple) => tuple.end(),
+        }
+    }
+}
+
+/// Serializes a single field of a message, or a null when the message doesn't have it
+pub struct Field<'a, M> {
+    msg: &'a M,
+    name: &'static str,
+}
+
+impl<'a, M> Field<'a, M> {
+    pub fn new(msg: &'a M, name: &'static str) -> Self {
+        Field { msg, name }
+    }
+}
+
+impl<'a, M: Serialize> Serialize for Field<'a, M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.msg.serialize(FieldSerializer {
+            inner: serializer,
+            name: self.name,
+        })
+    }
+}
+
+struct FieldSerializer<S> {
+    inner: S,
+    name: &'static str,
+}
+
+impl<S: Serializer> Serializer for FieldSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeStructVariant = FieldPicker<S>;
+
+    reject_non_variants!(S::Error);
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<FieldPicker<S>, S::Error> {
+        Ok(FieldPicker {
+            inner: Some(self.inner),
+            name: self.name,
+            picked: None,
+        })
+    }
+}
+
+struct FieldPicker<S: Serializer> {
+    inner: Option<S>,
+    name: &'static str,
+    picked: Option<S::Ok>,
+}
+
+impl<S: Serializer> SerializeStructVariant for FieldPicker<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), S::Error> {
+        if key == self.name {
+            if let Some(inner) = self.inner.take() {
+                self.picked = Some(value.serialize(inner)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        match (self.picked, self.inner) {
+            (Some(picked), _) => Ok(picked),
+            (None, Some(inner)) => inner.serialize_none(),
+            (None, None) => Err(ser::Error::custom("Field was visited but not written")),
+        }
+    }
+}
+
+/// Deserializes `Variant { rqs_id: id, trace, ..body }` out of a body holding only the params
+pub struct Envelope<'a, D> {
+    variant: &'a str,
+    id: Option<Id>,
+    trace: Option<TraceContext>,
+    body: D,
+}
+
+impl<'a, D> Envelope<'a, D> {
+    pub fn new(variant: &'a str, id: Option<Id>, body: D) -> Self {
+        Envelope {
+            variant,
+            id,
+            trace: None,
+            body,
+        }
+    }
+}
+
+impl<'de, 'a, D: Deserializer<'de>> Deserializer<'de> for Envelope<'a, D> {
+    type Error = D::Error;
+
+    fn is_human_readable(&self) -> bool {
+        self.body.is_human_readable()
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, D::Error> {
+        Err(de::Error::custom(NOT_A_VARIANT))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        visitor.visit_enum(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a, D: Deserializer<'de>> EnumAccess<'de> for Envelope<'a, D> {
+    type Error = D::Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), D::Error> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, D: Deserializer<'de>> VariantAccess<'de> for Envelope<'a, D> {
+    type Error = D::Error;
+
+    fn unit_variant(self) -> Result<(), D::Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, _: T) -> Result<T::Value, D::Error> {
+        Err(de::Error::custom(NOT_A_VARIANT))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, _: V) -> Result<V::Value, D::Error> {
+        Err(de::Error::custom(NOT_A_VARIANT))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.body.deserialize_any(Inject {
+            id: self.id,
+            trace: self.trace,
+            visitor,
+        })
+    }
+}
+
+/// Deserializes a message with `Envelope` from whatever deserializer holds its params
+pub struct EnvelopeSeed<'a, M> {
+    variant: &'a str,
+    id: Option<Id>,
+    trace: Option<TraceContext>,
+    message: PhantomData<M>,
+}
+
+impl<'a, M> EnvelopeSeed<'a, M> {
+    pub fn new(variant: &'a str, id: Option<Id>) -> Self {
+        EnvelopeSeed {
+            variant,
+            id,
+            trace: None,
+            message: PhantomData,
+        }
+    }
+
+    /// Hands the message a trace that came in the envelope rather than in the params
+    #[cfg(feature = "json")]
+    pub fn traced(mut self, trace: Option<TraceContext>) -> Self {
+        self.trace = trace;
+        self
+    }
+}
+
+impl<'de, 'a, M: DeserializeOwned> DeserializeSeed<'de> for EnvelopeSeed<'a, M> {
+    type Value = M;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<M, D::Error> {
+        let mut envelope = Envelope::new(self.variant, self.id, deserializer);
+        envelope.trace = self.trace;
+
+        M::deserialize(envelope)
+    }
+}
+
+struct Inject<V> {
+    id: Option<Id>,
+    trace: Option<TraceContext>,
+    visitor: V,
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for Inject<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "named or positional params")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<V::Value, A::Error> {
+        let injected = self.id.map(Injected::Id).into_iter();
+
+        self.visitor.visit_map(InjectedMap {
+            pending: injected.chain(self.trace.map(Injected::Trace)).collect(),
+            value: None,
+            inner: map,
+        })
+    }
+
+    // Positional params are in declaration order, and the id is always declared first. A trace
+    // is already among them, last
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_seq(InjectedSeq {
+            id: self.id,
+            inner: seq,
+        })
+    }
+}
+
+enum Injected {
+    Id(Id),
+    Trace(TraceContext),
+}
+
+struct InjectedMap<A> {
+    /// Entries still to go ahead of the params, first to last
+    pending: Vec<Injected>,
+    /// The entry whose key was just handed out
+    value: Option<Injected>,
+    inner: A,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for InjectedMap<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        if self.pending.is_empty() {
+            return self.inner.next_key_seed(seed);
+        }
+
+        let injected = self.pending.remove(0);
+        let key = match injected {
+            Injected::Id(_) => ID_FIELD,
+            Injected::Trace(_) => TRACE_FIELD,
+        };
+        self.value = Some(injected);
+
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, A::Error> {
+        match self.value.take() {
+            Some(Injected::Id(id)) => seed.deserialize(IdDeserializer::new(id)),
+            Some(Injected::Trace(trace)) => seed.deserialize(TraceDeserializer::new(trace)),
+            None => self.inner.next_value_seed(seed),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner
+            .size_hint()
+            .map(|size| size + self.pending.len() + self.value.is_some() as usize)
+    }
+}
+
+struct InjectedSeq<A> {
+    id: Option<Id>,
+    inner: A,
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for InjectedSeq<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, A::Error> {
+        match self.id.take() {
+            Some(id) => seed.deserialize(IdDeserializer::new(id)).map(Some),
+            None => self.inner.next_element_seed(seed),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner
+            .size_hint()
+            .map(|size| size + self.id.is_some() as usize)
+    }
+}
+
+struct IdDeserializer<E> {
+    id: Id,
+    error: PhantomData<E>,
+}
+
+impl<E> IdDeserializer<E> {
+    fn new(id: Id) -> Self {
+        IdDeserializer {
+            id,
+            error: PhantomData,
+        }
+    }
+}
+
+impl<'de, E: de::Error> Deserializer<'de> for IdDeserializer<E> {
+    type Error = E;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, E> {
+        match self.id {
+            Id::Number(id) => visitor.visit_i64(id),
+            Id::String(id) => visitor.visit_string(id),
+            Id::Null => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, E> {
+        match self.id {
+            Id::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// A trace as the `Option<TraceContext>` field of a request expects it
+struct TraceDeserializer<E> {
+    trace: TraceContext,
+    error: PhantomData<E>,
+}
+
+impl<E> TraceDeserializer<E> {
+    fn new(trace: TraceContext) -> Self {
+        TraceDeserializer {
+            trace,
+            error: PhantomData,
+        }
+    }
+}
+
+impl<'de, E: de::Error> Deserializer<'de> for TraceDeserializer<E> {
+    type Error = E;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, E> {
+        visitor.visit_string(self.trace.to_string())
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, E> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// A map with a single `error` entry, read from the error slot of a response
+pub struct ErrorBody<D> {
+    error: D,
+}
+
+impl<D> ErrorBody<D> {
+    pub fn new(error: D) -> Self {
+        ErrorBody { error }
+    }
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for ErrorBody<D> {
+    type Error = D::Error;
+
+    fn is_human_readable(&self) -> bool {
+        self.error.is_human_readable()
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        visitor.visit_map(ErrorEntry {
+            key_sent: false,
+            error: Some(self.error),
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct ErrorEntry<D> {
+    key_sent: bool,
+    error: Option<D>,
+}
+
+impl<'de, D: Deserializer<'de>> MapAccess<'de> for ErrorEntry<D> {
+    type Error = D::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, D::Error> {
+        if self.key_sent {
+            return Ok(None);
+        }
+
+        self.key_sent = true;
+        seed.deserialize(ERROR_FIELD.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, D::Error> {
+        match self.error.take() {
+            Some(error) => seed.deserialize(error),
+            None => Err(de::Error::custom("Error value was already read")),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ErrorCode, RpcError};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Request {
+        Create {
+            rqs_id: u32,
+            title: String,
+            trace: Option<TraceContext>,
+        },
+        Notify {
+            title: String,
+        },
+    }
+
+    const TRACE: TraceContext = TraceContext {
+        trace_id: 1,
+        span_id: 2,
+    };
+
+    fn create(trace: Option<TraceContext>) -> Request {
+        Request::Create {
+            rqs_id: 7,
+            title: "Groceries".to_string(),
+            trace,
+        }
+    }
+
+    fn decode<'de, D: Deserializer<'de>>(seed: EnvelopeSeed<Request>, params: D) -> Request {
+        seed.deserialize(params).unwrap()
+    }
+
+    #[test]
+    fn probes_without_serializing_values() {
+        let probed = probe(&create(None)).unwrap();
+        assert_eq!(probed.variant, "Create");
+        assert!(probed.has_id && probed.has_trace);
+
+        let probed = probe(&Request::Notify {
+            title: "Groceries".to_string(),
+        })
+        .unwrap();
+        assert!(!probed.has_id && !probed.has_trace);
+    }
+
+    #[test]
+    fn named_params_leave_out_the_id_and_trace() {
+        let params = serde_json::to_value(Params::named(&create(Some(TRACE)))).unwrap();
+
+        assert_eq!(params, json!({ "title": "Groceries" }));
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    #[test]
+    fn positional_params_keep_the_trace_last() {
+        let params = serde_json::to_value(Params::positional(&create(Some(TRACE)), 1)).unwrap();
+
+        assert_eq!(params, json!(["Groceries", TRACE.to_string()]));
+    }
+
+    #[test]
+    fn fields_are_picked_or_null() {
+        let msg = create(None);
+
+        assert_eq!(
+            serde_json::to_value(Field::new(&msg, ID_FIELD)).unwrap(),
+            json!(7)
+        );
+        assert_eq!(
+            serde_json::to_value(Field::new(&msg, "missing")).unwrap(),
+            json!(null)
+        );
+    }
+
+    #[test]
+    fn injects_the_id_and_trace_into_named_params() {
+        let seed = EnvelopeSeed::new("Create", Some(Id::from(7u32))).traced(Some(TRACE));
+        let params = json!({ "title": "Groceries" });
+
+        assert_eq!(decode(seed, params), create(Some(TRACE)));
+    }
+
+    #[test]
+    fn requests_without_a_trace_have_none() {
+        let seed = EnvelopeSeed::new("Create", Some(Id::from(7u32)));
+        let params = json!({ "title": "Groceries" });
+
+        assert_eq!(decode(seed, params), create(None));
+    }
+
+    #[test]
+    fn injects_the_id_ahead_of_positional_params() {
+        let seed = EnvelopeSeed::new("Create", Some(Id::from(7u32)));
+        let params = json!(["Groceries", TRACE.to_string()]);
+
+        assert_eq!(decode(seed, params), create(Some(TRACE)));
+    }
+
+    #[test]
+    fn rejects_ids_the_message_cannot_hold() {
+        let seed = EnvelopeSeed::<Request>::new("Create", Some(Id::from("seven")));
+
+        assert!(seed.deserialize(json!({ "title": "Groceries" })).is_err());
+    }
+
+    #[test]
+    fn reads_error_objects_as_an_error_field() {
+        #[derive(Deserialize)]
+        struct Failed {
+            error: RpcError,
+        }
+
+        let error = json!({ "code": -32001, "message": "Note not found" });
+        let failed = Failed::deserialize(ErrorBody::new(error)).unwrap();
+
+        assert_eq!(failed.error.code, ErrorCode::NotFound);
+        assert_eq!(failed.error.message, "Note not found");
+    }
+}
@@ -2,18 +2,17 @@ mod parsing;
 
 use std::marker::PhantomData;
 
-use bytes::{buf::*, BytesMut};
+use bytes::{Buf, BytesMut};
 
 use failure::Error;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use tracing::info;
-
 use serde_json as json;
+use serde_json::value::RawValue;
 
-use crate::{RpcMessage, RpcMessageType};
+use crate::{DecodeError, Decoded, ErrorCode, Packet, RpcError, RpcMessage};
 
 use parsing::*;
 
@@ -33,19 +32,7 @@ impl<Out: Serialize + RpcMessage> tokio_util::codec::Encoder<Out> for Encoder<Ou
     type Error = Error;
 
     fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), Error> {
-        let val = json::to_value(&msg)?;
-        let msg = match msg.rpc_message_type() {
-            RpcMessageType::Request => parsed_encoded_request(&val)?,
-            RpcMessageType::Notification => parsed_encoded_notification(&val)?,
-            RpcMessageType::Response => parsed_encoded_response(&val)?,
-            RpcMessageType::Error => parsed_encoded_error(&val)?,
-        };
-        info!("Encoding: {:?}", msg);
-
-        let msg: &[u8] = &json::to_vec(&msg)?;
-        dst.put(msg);
-
-        Ok(())
+        encode_message(&msg, dst)
     }
 }
 
@@ -62,23 +49,174 @@ impl<In> Default for Decoder<In> {
 }
 
 impl<In: DeserializeOwned> tokio_util::codec::Decoder for Decoder<In> {
-    type Item = In;
+    type Item = Decoded<In>;
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
         if src.is_empty() {
             return Ok(None);
         }
-        let mut de: json::StreamDeserializer<_, json::Value> =
-            json::Deserializer::from_reader(src.reader()).into_iter();
-        match de.next() {
-            Some(Ok(v)) => {
-                info!("Decoding: {:?}", v);
-                Ok(Some(parse_decoded(&v)?))
+
+        // Single messages are decoded in one pass, results included. Batches, and messages that
+        // fail that pass, are read whole first so the error can be pinned down
+        if src.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'[') {
+            let (next, consumed) = {
+                let mut de = json::Deserializer::from_slice(&src[..]).into_iter::<Incoming<In>>();
+                let next = de.next().map(|msg| msg.map(parse_incoming));
+                (next, de.byte_offset())
+            };
+
+            match next {
+                Some(Ok(msg)) => {
+                    src.advance(consumed);
+                    return Ok(Some(Packet::Single(Ok(msg?))));
+                }
+                Some(Err(e)) if e.is_eof() => return Ok(None),
+                Some(Err(_)) => {}
+                None => {
+                    // Only whitespace was left
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+        }
+
+        // The message is read straight out of the buffer, so it has to be parsed before the
+        // bytes it borrows from are released
+        let (next, consumed) = {
+            let mut de = json::Deserializer::from_slice(&src[..]).into_iter::<&RawValue>();
+            let next = de.next().map(|raw| raw.map(parse_decoded_packet));
+            (next, de.byte_offset())
+        };
+
+        match next {
+            Some(Ok(packet)) => {
+                src.advance(consumed);
+                Ok(Some(packet?))
             }
             Some(Err(e)) if e.is_eof() => Ok(None),
-            Some(Err(e)) => Err(e.into()),
-            None => Ok(None),
+            Some(Err(e)) => {
+                resync(src);
+                Err(DecodeError::from(RpcError::new(ErrorCode::ParseError, e.to_string())).into())
+            }
+            None => {
+                // Only whitespace was left
+                src.clear();
+                Ok(None)
+            }
+        }
+    }
+}
+
+// Without framing there's no telling where the bad message ends, so skip ahead to the
+// next byte that could open one
+fn resync(src: &mut BytesMut) {
+    let next = src
+        .iter()
+        .skip(1)
+        .position(|b| *b == b'{' || *b == b'[')
+        .map_or(src.len(), |idx| idx + 1);
+
+    src.advance(next);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tokio_util::codec::Decoder as _;
+
+    use crate::{Decoder, ErrorCode, Format, Id, Packet};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Request {
+        Say { rqs_id: Id, words: String },
+        Poke {},
+    }
+
+    fn decode(json: &str) -> Decoded<Request> {
+        let mut src = BytesMut::from(json);
+        Decoder::new(Format::Json)
+            .decode(&mut src)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn say(id: u32) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"Say","params":{{"words":"hi"}},"id":{}}}"#,
+            id
+        )
+    }
+
+    #[test]
+    fn answers_each_message_of_a_batch_on_its_own() {
+        let shout = r#"{"jsonrpc":"2.0","method":"Shout","params":{},"id":2}"#;
+        let batch = format!("[{},{},1,{}]", say(1), shout, say(3));
+
+        let decoded = match decode(&batch) {
+            Packet::Batch(decoded) => decoded,
+            packet => panic!("Expected a batch, got {:?}", packet),
+        };
+        assert_eq!(decoded.len(), 4);
+
+        let said = |id: u32| Request::Say {
+            rqs_id: Id::from(id),
+            words: "hi".to_string(),
+        };
+        assert_eq!(decoded[0].as_ref().unwrap(), &said(1));
+        assert_eq!(decoded[3].as_ref().unwrap(), &said(3));
+
+        let unknown = decoded[1].as_ref().unwrap_err();
+        assert_eq!(unknown.id, Some(Id::from(2u32)));
+        assert_eq!(unknown.error.code, ErrorCode::MethodNotFound);
+
+        let garbage = decoded[2].as_ref().unwrap_err();
+        assert_eq!(garbage.id, None);
+        assert_eq!(garbage.error.code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn rejects_an_empty_batch_as_a_whole() {
+        match decode("[]") {
+            Packet::Single(Err(e)) => {
+                assert_eq!(e.id, None);
+                assert_eq!(e.error.code, ErrorCode::InvalidRequest);
+            }
+            packet => panic!("Expected a single error, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn decodes_batches_of_notifications() {
+        let poke = r#"{"jsonrpc":"2.0","method":"Poke"}"#;
+
+        match decode(&format!("[{},{}]", poke, poke)) {
+            Packet::Batch(decoded) => {
+                assert_eq!(decoded.len(), 2);
+                assert!(decoded
+                    .iter()
+                    .all(|r| r.as_ref().ok() == Some(&Request::Poke {})));
+            }
+            packet => panic!("Expected a batch, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn carries_on_after_a_message_that_does_not_parse() {
+        let mut decoder = Decoder::<Request>::new(Format::Json);
+        let mut src = BytesMut::from(format!("{{\"jsonrpc\": nope}} {}", say(1)).as_str());
+
+        match decoder.decode(&mut src).unwrap() {
+            Some(Packet::Single(Err(e))) => assert_eq!(e.error.code, ErrorCode::ParseError),
+            packet => panic!("Expected a parse error, got {:?}", packet),
+        }
+        match decoder.decode(&mut src).unwrap() {
+            Some(Packet::Single(Ok(Request::Say { rqs_id, .. }))) => {
+                assert_eq!(rqs_id, Id::from(1u32))
+            }
+            packet => panic!("Expected the next message, got {:?}", packet),
         }
     }
 }
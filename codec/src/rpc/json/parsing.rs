@@ -1,128 +1,232 @@
+use std::borrow::Cow;
+
+use bytes::{buf::*, BytesMut};
+
 use failure::{format_err, Error};
 
 use tracing::{error, info};
 
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, DeserializeSeed};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use serde_json as json;
-
-pub fn parsed_encoded_error(value: &json::Value) -> Result<json::Value, Error> {
-    let err = format_err!(
-        "Encoding Error: Expected encoded Object, found: {:?}",
-        value
-    );
-
-    let map = parse_map(value)?;
-    let (_method, params) = map.iter().next().ok_or(err)?;
-    let id = params["rqs_id"].clone();
-
-    Ok(json::json!({
-        "jsonrpc": "2.0",
-        "error": value,
-        "result": json::Value::Null,
-        "id": id
-    }))
+use serde_json::value::RawValue;
+
+use crate::error::ERROR_VARIANT;
+use crate::rpc::envelope::{self, EnvelopeSeed, ErrorBody, Field, Params};
+use crate::{
+    DecodeError, Decoded, ErrorCode, Id, Packet, RpcError, RpcMessage, RpcMessageType, TraceContext,
+};
+
+#[derive(Serialize)]
+struct Outgoing<'a, M> {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<Params<'a, M>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a M>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Field<'a, M>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Field<'a, M>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<Field<'a, M>>,
 }
 
-pub fn parsed_encoded_response(value: &json::Value) -> Result<json::Value, Error> {
-    let err = format_err!(
-        "Encoding Error: Expected encoded Object, found: {:?}",
-        value
-    );
-
-    let map = parse_map(value)?;
-    let (_method, params) = map.iter().next().ok_or(err)?;
-    let id = params["rqs_id"].clone();
-
-    Ok(json::json!({
-        "jsonrpc": "2.0",
-        "result": value,
-        "error": json::Value::Null,
-        "id": id
-    }))
-}
+pub fn encode_message<Out: Serialize + RpcMessage>(
+    msg: &Out,
+    dst: &mut BytesMut,
+) -> Result<(), Error> {
+    let probe = envelope::probe(msg).map_err(|e| format_err!("Encoding Error: {}", e))?;
+    let mut outgoing = Outgoing {
+        jsonrpc: "2.0",
+        method: None,
+        params: None,
+        result: None,
+        error: None,
+        id: Some(Field::new(msg, envelope::ID_FIELD)),
+        trace: None,
+    };
 
-pub fn parsed_encoded_notification(value: &json::Value) -> Result<json::Value, Error> {
-    let err = format_err!(
-        "Encoding Error: Expected encoded Object, found: {:?}",
-        value
-    );
+    match msg.rpc_message_type() {
+        RpcMessageType::Request => {
+            outgoing.method = Some(probe.variant);
+            outgoing.params = Some(Params::named(msg));
+            if probe.has_trace {
+                outgoing.trace = Some(Field::new(msg, envelope::TRACE_FIELD));
+            }
+        }
+        RpcMessageType::Notification => {
+            outgoing.method = Some(probe.variant);
+            outgoing.params = Some(Params::named(msg));
+            outgoing.id = None;
+        }
+        RpcMessageType::Response => outgoing.result = Some(msg),
+        RpcMessageType::Error => outgoing.error = Some(Field::new(msg, envelope::ERROR_FIELD)),
+    };
+    info!("Encoding: {}", probe.variant);
 
-    let map = parse_map(value)?;
-    let (method, params) = map.iter().next().ok_or(err)?;
+    json::to_writer(dst.writer(), &outgoing)?;
 
-    Ok(json::json!({
-        "jsonrpc": "2.0",
-        "method": method,
-        "params": params,
-    }))
+    Ok(())
 }
 
-pub fn parsed_encoded_request(value: &json::Value) -> Result<json::Value, Error> {
-    let err = format_err!(
-        "Encoding Error: Expected encoded Object, found: {:?}",
-        value
-    );
+/// A message as it appears on the wire. Results are decoded in place (`R = In`) unless the
+/// message is being re-read from a raw value to find out what's wrong with it
+#[derive(Deserialize)]
+pub struct Incoming<'a, R> {
+    #[serde(borrow)]
+    method: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    params: Option<&'a RawValue>,
+    result: Option<R>,
+    #[serde(borrow)]
+    error: Option<&'a RawValue>,
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Id>,
+    #[serde(default)]
+    trace: Option<TraceContext>,
+}
 
-    let map = parse_map(value)?;
-    let method = map.keys().next().ok_or(err)?;
+// Tells a `null` id apart from a missing one
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Id>, D::Error> {
+    Id::deserialize(deserializer).map(Some)
+}
 
-    let mut value = value.clone();
+pub fn parse_incoming<In: DeserializeOwned>(incoming: Incoming<In>) -> Result<In, DecodeError> {
+    let id = incoming.id.clone();
 
-    let mut params = value[method].take();
-    let id = params["rqs_id"].take();
+    decode_message(incoming).map_err(|error| DecodeError::new(id, error))
+}
 
-    Ok(json::json!({
-        "jsonrpc": "2.0",
-        "method": method,
-        "params": params,
-        "id": id
-    }))
+pub fn parse_decoded_packet<In: DeserializeOwned>(raw: &RawValue) -> Result<Decoded<In>, Error> {
+    if !raw.get().starts_with('[') {
+        return Ok(Packet::Single(Ok(parse_decoded(raw)?)));
+    }
+
+    let raws: Vec<&RawValue> = json::from_str(raw.get())
+        .map_err(|e| DecodeError::from(RpcError::new(ErrorCode::InvalidRequest, e.to_string())))?;
+    if raws.is_empty() {
+        return Err(
+            DecodeError::from(RpcError::new(ErrorCode::InvalidRequest, "Empty batch")).into(),
+        );
+    }
+
+    info!("Decoding Batch of {}", raws.len());
+    // Each message is answered on its own, so a bad one doesn't hold up the rest
+    Ok(Packet::Batch(raws.into_iter().map(parse_decoded).collect()))
 }
 
-pub fn parse_decoded<In: DeserializeOwned>(value: &json::Value) -> Result<In, Error> {
-    let opt_id = value.get("id").map(|v| v.as_u64()).flatten();
-    let opt_method = value.get("method").map(|v| v.as_str()).flatten();
-    let opt_params = value.get("params").map(|v| v.as_object()).flatten();
-    let opt_result = value.get("result");
-    let opt_error = value.get("error");
-
-    let parsed = match (opt_method, opt_params, opt_result, opt_error) {
-        (Some(method), Some(params), None, _) => {
-            let mut params = params.clone();
-            if let Some(id) = opt_id {
-                params.insert("rqs_id".to_string(), json::json!(id));
-            }
-            info!("Decoding Request: {:?}: {:?}", method, params);
-            json::json!({ method: params })
+pub fn parse_decoded<In: DeserializeOwned>(raw: &RawValue) -> Result<In, DecodeError> {
+    let incoming: Incoming<&RawValue> = json::from_str(raw.get()).map_err(|e| {
+        DecodeError::from(RpcError::new(
+            ErrorCode::InvalidRequest,
+            format!("Unknown json-rpc format: {}", e),
+        ))
+    })?;
+
+    let Incoming {
+        method,
+        params,
+        result,
+        error,
+        id,
+        trace,
+    } = incoming;
+    let result = match result {
+        Some(res) if res.get().starts_with('{') => {
+            let res = json::from_str(res.get()).map_err(|e| {
+                DecodeError::new(
+                    id.clone(),
+                    RpcError::new(ErrorCode::InvalidRequest, e.to_string()),
+                )
+            })?;
+
+            Some(res)
         }
-        (None, None, Some(res), _) if res.is_object() => {
-            info!("Decoding Result: {:?}", res);
-            res.clone()
-        }
-        (None, None, None, Some(err)) if err.is_object() => {
-            info!("Decoding Error: {:?}", err);
-            err.clone()
-        }
-        _ => {
-            error!(
-                "Error Decoding {:?} ({:?}, {:?}, {:?}, {:?})",
-                value, opt_method, opt_params, opt_result, opt_error
-            );
-            return Err(format_err!(
-                "Decoding Error: Unknown json-rpc format: {:?}",
-                value
+        Some(res) => {
+            error!("Error Decoding {}", raw);
+
+            return Err(DecodeError::new(
+                id,
+                RpcError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Expected a result object, found: {}", res),
+                ),
             ));
         }
+        None => None,
     };
 
-    info!("Decoding parsed: {:?}", parsed);
+    parse_incoming(Incoming {
+        method,
+        params,
+        result,
+        error,
+        id,
+        trace,
+    })
+}
+
+fn decode_message<In: DeserializeOwned>(msg: Incoming<In>) -> Result<In, RpcError> {
+    let Incoming {
+        method,
+        params,
+        result,
+        error,
+        id,
+        trace,
+    } = msg;
+
+    match (method, params, result, error) {
+        (Some(method), params, None, None) => {
+            let params = params.map_or("{}", RawValue::get);
+            if !params.starts_with('{') {
+                return Err(RpcError::new(
+                    ErrorCode::InvalidParams,
+                    format!("Expected named params, found: {}", params),
+                )
+                .with_method(method));
+            }
+            info!("Decoding Request: {:?}", method);
+
+            EnvelopeSeed::new(&method, id)
+                .traced(trace)
+                .deserialize(&mut json::Deserializer::from_str(params))
+                .map_err(|e| request_error(&method, e))
+        }
+        (None, None, Some(res), None) => {
+            info!("Decoding Result");
+            Ok(res)
+        }
+        (None, None, None, Some(err)) if err.get().starts_with('{') => {
+            info!("Decoding Error: {}", err);
+
+            let mut body = json::Deserializer::from_str(err.get());
+            EnvelopeSeed::new(ERROR_VARIANT, Some(id.unwrap_or(Id::Null)))
+                .deserialize(ErrorBody::new(&mut body))
+                .map_err(|e| RpcError::new(ErrorCode::InvalidRequest, e.to_string()))
+        }
+        (method, params, _, error) => {
+            error!("Error Decoding ({:?}, {:?}, {:?})", method, params, error);
 
-    Ok(json::from_value(parsed)?)
+            Err(RpcError::new(
+                ErrorCode::InvalidRequest,
+                "Unknown json-rpc format: expected a request, a result or an error",
+            ))
+        }
+    }
 }
 
-fn parse_map(value: &json::Value) -> Result<json::Map<String, json::Value>, Error> {
-    let err = format_err!("Encoding Error: Expected encoded Map, found: {:?}", value);
+fn request_error(method: &str, error: json::Error) -> RpcError {
+    let message = error.to_string();
+    let code = if message.starts_with("unknown variant") {
+        ErrorCode::MethodNotFound
+    } else {
+        ErrorCode::InvalidParams
+    };
 
-    value.as_object().cloned().ok_or(err)
+    RpcError::new(code, message).with_method(method)
 }
@@ -1,11 +1,374 @@
+#[cfg(not(any(feature = "json", feature = "msgpack", feature = "cbor")))]
+compile_error!("codec requires at least one of the `json`, `msgpack` or `cbor` features");
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+mod array;
+mod envelope;
+
 #[cfg(feature = "msgpack")]
 mod msgpack;
 
-#[cfg(feature = "msgpack")]
-pub use msgpack::{Encoder, Decoder};
+#[cfg(feature = "cbor")]
+mod cbor;
 
 #[cfg(feature = "json")]
 mod json;
 
 #[cfg(feature = "json")]
-pub use json::{Encoder, Decoder};
+use bytes::BufMut;
+use bytes::BytesMut;
+
+use failure::{format_err, Error};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use tokio_util::codec::{Decoder as _, Encoder as _};
+
+use crate::framing::Frames;
+use crate::{
+    Compression, DecodeError, Decoded, ErrorCode, Framing, Packet, RpcError, RpcMessage,
+    DEFAULT_MAX_FRAME_SIZE,
+};
+
+/// The wire formats a connection can be negotiated into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    /// Every format compiled into this build, in order of preference
+    pub fn available() -> Vec<Format> {
+        vec![
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack,
+            #[cfg(feature = "cbor")]
+            Format::Cbor,
+            #[cfg(feature = "json")]
+            Format::Json,
+        ]
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "json")]
+            Format::Json => 1,
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => 2,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Format> {
+        Format::available()
+            .into_iter()
+            .find(|format| format.id() == id)
+    }
+}
+
+enum PayloadEncoder<Out> {
+    #[cfg(feature = "json")]
+    Json(json::Encoder<Out>),
+    #[cfg(feature = "msgpack")]
+    MsgPack(msgpack::Encoder<Out>),
+    #[cfg(feature = "cbor")]
+    Cbor(cbor::Encoder<Out>),
+}
+
+impl<Out: Serialize + RpcMessage> PayloadEncoder<Out> {
+    fn encode(&mut self, packet: Packet<Out>, dst: &mut BytesMut) -> Result<(), Error> {
+        match (self, packet) {
+            #[cfg(feature = "json")]
+            (PayloadEncoder::Json(encoder), Packet::Single(msg)) => encoder.encode(msg, dst),
+            #[cfg(feature = "json")]
+            (PayloadEncoder::Json(encoder), Packet::Batch(msgs)) => {
+                dst.put_u8(b'[');
+                for (idx, msg) in msgs.into_iter().enumerate() {
+                    if idx > 0 {
+                        dst.put_u8(b',');
+                    }
+                    encoder.encode(msg, dst)?;
+                }
+                dst.put_u8(b']');
+
+                Ok(())
+            }
+            // msgpack-rpc has no notion of batches, so they go out as consecutive messages
+            #[cfg(feature = "msgpack")]
+            (PayloadEncoder::MsgPack(encoder), packet) => packet
+                .into_iter()
+                .try_for_each(|msg| encoder.encode(msg, dst)),
+            // Neither does its CBOR counterpart
+            #[cfg(feature = "cbor")]
+            (PayloadEncoder::Cbor(encoder), packet) => packet
+                .into_iter()
+                .try_for_each(|msg| encoder.encode(msg, dst)),
+        }
+    }
+}
+
+pub struct Encoder<Out> {
+    payload: PayloadEncoder<Out>,
+    framing: Option<Frames>,
+}
+
+impl<Out> Encoder<Out> {
+    pub fn new(format: Format) -> Self {
+        Self::with_framing(format, Framing::Stream)
+    }
+
+    pub fn with_framing(format: Format, framing: Framing) -> Self {
+        let payload = match format {
+            #[cfg(feature = "json")]
+            Format::Json => PayloadEncoder::Json(json::Encoder::default()),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => PayloadEncoder::MsgPack(msgpack::Encoder::default()),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => PayloadEncoder::Cbor(cbor::Encoder::default()),
+        };
+
+        Encoder {
+            payload,
+            framing: framing.frames(),
+        }
+    }
+
+    /// Compresses frames past the threshold. Streamed messages can't be flagged as compressed,
+    /// so without length-delimited framing this does nothing
+    pub fn compressed(mut self, compression: Compression) -> Self {
+        if let Some(frames) = self.framing.as_mut() {
+            frames.compression = compression;
+        }
+
+        self
+    }
+}
+
+impl<Out: Serialize + RpcMessage> tokio_util::codec::Encoder<Packet<Out>> for Encoder<Out> {
+    type Error = Error;
+
+    fn encode(&mut self, packet: Packet<Out>, dst: &mut BytesMut) -> Result<(), Error> {
+        match self.framing.as_ref() {
+            None => self.payload.encode(packet, dst),
+            Some(framing) => {
+                // The whole packet goes in one frame, batches included
+                let mut frame = BytesMut::new();
+                self.payload.encode(packet, &mut frame)?;
+
+                framing.encode(&frame, dst)
+            }
+        }
+    }
+}
+
+enum PayloadDecoder<In> {
+    #[cfg(feature = "json")]
+    Json(json::Decoder<In>),
+    #[cfg(feature = "msgpack")]
+    MsgPack(msgpack::Decoder<In>),
+    #[cfg(feature = "cbor")]
+    Cbor(cbor::Decoder<In>),
+}
+
+impl<In: DeserializeOwned> PayloadDecoder<In> {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Decoded<In>>, Error> {
+        match self {
+            #[cfg(feature = "json")]
+            PayloadDecoder::Json(decoder) => decoder.decode(src),
+            #[cfg(feature = "msgpack")]
+            PayloadDecoder::MsgPack(decoder) => decoder.decode(src),
+            #[cfg(feature = "cbor")]
+            PayloadDecoder::Cbor(decoder) => decoder.decode(src),
+        }
+    }
+
+    fn decode_frame(&mut self, mut frame: BytesMut) -> Result<Decoded<In>, Error> {
+        let mut packets = vec![];
+        while !frame.is_empty() {
+            match self.decode(&mut frame)? {
+                Some(packet) => packets.push(packet),
+                None if frame.is_empty() => break,
+                None => {
+                    return Err(malformed(
+                        ErrorCode::ParseError,
+                        "Incomplete message in frame",
+                    ))
+                }
+            }
+        }
+
+        match packets.len() {
+            0 => Err(malformed(
+                ErrorCode::InvalidRequest,
+                "Frame holds no message",
+            )),
+            1 => Ok(packets.remove(0)),
+            // Formats without batches (msgpack, cbor) send one message after the other
+            _ => Ok(Packet::Batch(packets.into_iter().flatten().collect())),
+        }
+    }
+}
+
+pub struct Decoder<In> {
+    payload: PayloadDecoder<In>,
+    framing: Option<Frames>,
+    // Most a streamed message may take up before it's given up on
+    max_message_size: usize,
+}
+
+impl<In> Decoder<In> {
+    pub fn new(format: Format) -> Self {
+        Self::with_framing(format, Framing::Stream)
+    }
+
+    pub fn with_framing(format: Format, framing: Framing) -> Self {
+        let payload = match format {
+            #[cfg(feature = "json")]
+            Format::Json => PayloadDecoder::Json(json::Decoder::default()),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => PayloadDecoder::MsgPack(msgpack::Decoder::default()),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => PayloadDecoder::Cbor(cbor::Decoder::default()),
+        };
+
+        Decoder {
+            payload,
+            framing: framing.frames(),
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Bounds how large a streamed message may grow, the way `Framing::LengthDelimited` bounds
+    /// frames
+    pub fn limited(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Expects frames flagged the way an `Encoder` with the same compression writes them
+    pub fn compressed(mut self, compression: Compression) -> Self {
+        if let Some(frames) = self.framing.as_mut() {
+            frames.compression = compression;
+        }
+
+        self
+    }
+}
+
+impl<In: DeserializeOwned> Decoder<In> {
+    fn decode_packet(&mut self, src: &mut BytesMut) -> Result<Option<Decoded<In>>, Error> {
+        match self.framing.as_mut() {
+            None => match self.payload.decode(src)? {
+                None if src.len() > self.max_message_size => {
+                    // Without frames there's no finding where the message ends to carry on after
+                    // it, so this isn't a `DecodeError` and nothing else gets read
+                    src.clear();
+                    Err(format_err!(
+                        "Decoding Error: Message exceeds the {} byte limit",
+                        self.max_message_size
+                    ))
+                }
+                packet => Ok(packet),
+            },
+            Some(framing) => match framing.decode(src)? {
+                Some(frame) => self.payload.decode_frame(frame).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl<In: DeserializeOwned> tokio_util::codec::Decoder for Decoder<In> {
+    type Item = Decoded<In>;
+    type Error = Error;
+
+    // Messages that can't be decoded were already skipped, so they're handed on to be answered
+    // and only errors that leave the rest of the stream unreadable end it
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Decoded<In>>, Error> {
+        match self.decode_packet(src) {
+            Err(e) => match e.downcast::<DecodeError>() {
+                Ok(e) => Ok(Some(Packet::Single(Err(e)))),
+                Err(e) => Err(e),
+            },
+            packet => packet,
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Decoded<In>>, Error> {
+        match self.decode(src)? {
+            Some(packet) => Ok(Some(packet)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                // Nothing else is coming, so the leftovers can only be reported once
+                src.clear();
+                let error = RpcError::new(ErrorCode::ParseError, "Connection closed mid-message");
+
+                Ok(Some(Packet::Single(Err(DecodeError::from(error)))))
+            }
+        }
+    }
+}
+
+fn malformed(code: ErrorCode, message: &str) -> Error {
+    DecodeError::from(RpcError::new(code, message)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+    use serde::Deserialize;
+
+    use crate::{Id, RpcMessageType};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Say { rqs_id: Id, words: String },
+    }
+
+    impl RpcMessage for Message {
+        fn rpc_message_type(&self) -> RpcMessageType {
+            RpcMessageType::Request
+        }
+    }
+
+    fn say() -> Message {
+        Message::Say {
+            rqs_id: Id::from(1u32),
+            words: "hi".to_string(),
+        }
+    }
+
+    #[test]
+    fn carries_on_after_a_frame_that_does_not_decode() {
+        for format in Format::available() {
+            // An array that promises three values and holds one, in any of the formats
+            let garbage = b"\x93\x00";
+            let mut src = BytesMut::new();
+            src.put_u32(garbage.len() as u32);
+            src.extend_from_slice(garbage);
+            Encoder::with_framing(format, Framing::length_delimited())
+                .encode(Packet::Single(say()), &mut src)
+                .unwrap();
+
+            let mut decoder = Decoder::<Message>::with_framing(format, Framing::length_delimited());
+            match decoder.decode(&mut src).unwrap() {
+                Some(Packet::Single(Err(e))) => assert_eq!(e.id, None, "{:?}", format),
+                packet => panic!("Expected an error from {:?}, got {:?}", format, packet),
+            }
+            match decoder.decode(&mut src).unwrap() {
+                Some(Packet::Single(Ok(message))) => assert_eq!(message, say(), "{:?}", format),
+                packet => panic!("Expected a message from {:?}, got {:?}", format, packet),
+            }
+            assert!(src.is_empty());
+        }
+    }
+}
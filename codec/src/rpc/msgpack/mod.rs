@@ -1,23 +1,24 @@
 mod parsing;
 
+use std::cmp;
 use std::marker::PhantomData;
 
-use bytes::{buf::*, BytesMut};
+use bytes::BytesMut;
 
-use failure::{format_err, Error};
+use failure::Error;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use rmp_serde as rmps;
-
 use tracing::info;
 
-use crate::{RpcMessage, RpcMessageType};
+use crate::{Decoded, Packet, RpcMessage};
 
 use parsing::*;
 
-#[derive(Default)]
+// Most room made for a value before its bytes arrive
+const RESERVE_CHUNK: usize = 64 * 1024;
+
 pub struct Encoder<Out> {
     outbound_message: PhantomData<Out>,
 }
@@ -30,33 +31,11 @@ impl<Out> Default for Encoder<Out> {
     }
 }
 
-fn get_rmp_value<Msg: Serialize>(msg: &Msg) -> Result<rmpv::Value, Error> {
-    let mut vec = Vec::new();
-    let mut serializer = rmps::encode::Serializer::new(&mut vec)
-        .with_string_variants()
-        .with_struct_map();
-    msg.serialize(&mut serializer)?;
-
-    Ok(rmpv::decode::read_value(&mut vec.reader())?)
-}
-
 impl<Out: Serialize + RpcMessage> tokio_util::codec::Encoder<Out> for Encoder<Out> {
     type Error = Error;
 
     fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), Error> {
-        let value = get_rmp_value(&msg)?;
-        let to_encode = match msg.rpc_message_type() {
-            RpcMessageType::Request => parse_encoded_request_value(&value)?,
-            RpcMessageType::Notification => parse_encoded_notification_value(&value)?,
-            RpcMessageType::Response => parse_encoded_response_value(&value)?,
-            RpcMessageType::Error => parse_encoded_error_value(&value)?,
-        };
-
-        info!("Encoding: {:?}", to_encode);
-
-        rmpv::encode::write_value(&mut dst.writer(), &to_encode)?;
-
-        Ok(())
+        encode_message(&msg, dst)
     }
 }
 
@@ -73,7 +52,7 @@ impl<In> Default for Decoder<In> {
 }
 
 impl<In: DeserializeOwned> tokio_util::codec::Decoder for Decoder<In> {
-    type Item = In;
+    type Item = Decoded<In>;
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
@@ -83,27 +62,21 @@ impl<In: DeserializeOwned> tokio_util::codec::Decoder for Decoder<In> {
 
         let size = match get_value_size(src) {
             Some(size) if src.len() >= size => size,
+            // The size comes from the peer, so room is only made for it a chunk at a time
             Some(size) => {
                 let needed = size - src.len() + 16;
-                src.reserve(needed);
+                src.reserve(cmp::min(needed, RESERVE_CHUNK));
 
                 return Ok(None);
             }
             None => return Ok(None),
         };
         let to_parse = src.split_to(size);
-        info!("Decoding: {:?}", to_parse.to_vec());
-        // TODO: Decode the rpc format passed back
-        let value = parse_array(&rmpv::decode::read_value(&mut to_parse.reader())?)?;
-
-        let parsed = match parse_int(&value[0])? {
-            0 => parse_decoded_request_value(&value)?,
-            1 => parse_decoded_result_value(&value)?,
-            2 => parse_decoded_notification_value(&value)?,
-            _ => return Err(format_err!("Unexpected Message type")),
-        };
+        info!("Decoding: {} bytes", to_parse.len());
+        // The value was split off already, so a bad one doesn't hold up the next
+        let parsed = parse_decoded(&to_parse)?;
 
-        Ok(parsed)
+        Ok(Some(Packet::Single(Ok(parsed))))
     }
 }
 
@@ -170,13 +143,13 @@ fn get_value_size(buffer: &[u8]) -> Option<usize> {
         0xa0..=0xbf => Some(1 + (buffer[0] & 0x1f) as usize), // 101XXXXX & 00011111
         // 8-bit size: str | bin
         0xd9 | 0xc4 if buffer.len() < 2 => None,
-        0xd9 | 0xc4 => Some(2 + get_be_size(&buffer, S8)),
+        0xd9 | 0xc4 => Some(2 + get_be_size(buffer, S8)),
         // 16-bit size: str | bin
         0xda | 0xc5 if buffer.len() < 3 => None,
-        0xda | 0xc5 => Some(3 + get_be_size(&buffer, S16)),
+        0xda | 0xc5 => Some(3 + get_be_size(buffer, S16)),
         // 32-but size: str | bin
         0xdb | 0xc6 if buffer.len() < 5 => None,
-        0xdb | 0xc6 => Some(5 + get_be_size(&buffer, S32)),
+        0xdb | 0xc6 => Some(5 + get_be_size(buffer, S32)),
         //
         // *Ext*
         // fixext(1)
@@ -191,32 +164,32 @@ fn get_value_size(buffer: &[u8]) -> Option<usize> {
         0xd8 => Some(18),
         // ext(8)
         0xc7 if buffer.len() < 2 => None,
-        0xc7 => Some(3 + get_be_size(&buffer, S8)),
+        0xc7 => Some(3 + get_be_size(buffer, S8)),
         // ext(16)
         0xc8 if buffer.len() < 3 => None,
-        0xc8 => Some(4 + get_be_size(&buffer, S16)),
+        0xc8 => Some(4 + get_be_size(buffer, S16)),
         // ext(32)
         0xc9 if buffer.len() < 5 => None,
-        0xc9 => Some(6 + get_be_size(&buffer, S32)),
+        0xc9 => Some(6 + get_be_size(buffer, S32)),
         //
         // *Array*
         // fixarray
-        0x90..=0x9f => size_compound(&buffer, (buffer[0] & 0x0f) as usize, 1),
+        0x90..=0x9f => size_compound(buffer, (buffer[0] & 0x0f) as usize, 1),
         // array(16)
         0xdc if buffer.len() < 3 => None,
-        0xdc => size_compound(&buffer, get_be_size(&buffer, S16), 3),
+        0xdc => size_compound(buffer, get_be_size(buffer, S16), 3),
         // array(32)
         0xdd if buffer.len() < 5 => None,
-        0xdd => size_compound(&buffer, get_be_size(&buffer, S32), 5),
+        0xdd => size_compound(buffer, get_be_size(buffer, S32), 5),
         //
         // *Map*
         // fixmap
-        0x80..=0x8f => size_compound(&buffer, (buffer[0] & 0x0f) as usize * 2, 1),
+        0x80..=0x8f => size_compound(buffer, (buffer[0] & 0x0f) as usize * 2, 1),
         // array(16)
         0xde if buffer.len() < 3 => None,
-        0xde => size_compound(&buffer, get_be_size(&buffer, S16) * 2, 3),
+        0xde => size_compound(buffer, get_be_size(buffer, S16) * 2, 3),
         // array(32)
         0xdf if buffer.len() < 5 => None,
-        0xdf => size_compound(&buffer, get_be_size(&buffer, S32) * 2, 5),
+        0xdf => size_compound(buffer, get_be_size(buffer, S32) * 2, 5),
     }
 }
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Ties a request to the others made on behalf of the same action. Travels as a W3C
+/// `traceparent`, `00-<trace id>-<span id>-01`, where the span is the caller's
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+impl TraceContext {
+    /// The same trace, seen from `span_id`
+    pub fn child(self, span_id: u64) -> Self {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id,
+        }
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = String;
+
+    fn from_str(traceparent: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid traceparent: {}", traceparent);
+
+        // Later versions may add fields, but keep these where they are
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        match parts.as_slice() {
+            [version, trace_id, span_id, _flags, ..]
+                if *version != "ff" && trace_id.len() == 32 && span_id.len() == 16 =>
+            {
+                Ok(TraceContext {
+                    trace_id: u128::from_str_radix(trace_id, 16).map_err(|_| invalid())?,
+                    span_id: u64::from_str_radix(span_id, 16).map_err(|_| invalid())?,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for TraceContext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TraceContext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(TraceVisitor)
+    }
+}
+
+struct TraceVisitor;
+
+impl<'de> Visitor<'de> for TraceVisitor {
+    type Value = TraceContext;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a traceparent string")
+    }
+
+    fn visit_str<E: de::Error>(self, traceparent: &str) -> Result<TraceContext, E> {
+        traceparent.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn parses_traceparents() {
+        let trace: TraceContext = TRACEPARENT.parse().unwrap();
+
+        assert_eq!(trace.trace_id, 0x0af7_6519_16cd_43dd_8448_eb21_1c80_319c);
+        assert_eq!(trace.span_id, 0xb7ad_6b71_6920_3331);
+        assert_eq!(trace.to_string(), TRACEPARENT);
+    }
+
+    #[test]
+    fn takes_later_versions_with_extra_fields() {
+        let later = format!("cc{}-extra", &TRACEPARENT[2..]);
+
+        assert_eq!(
+            later.parse::<TraceContext>(),
+            TRACEPARENT.parse::<TraceContext>()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        for traceparent in &[
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71-01",
+            "00-zzf7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            assert!(
+                traceparent.parse::<TraceContext>().is_err(),
+                "{}",
+                traceparent
+            );
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn serializes_as_a_traceparent_string() {
+        let trace: TraceContext = TRACEPARENT.parse().unwrap();
+        let json = serde_json::to_string(&trace).unwrap();
+
+        assert_eq!(json, format!("\"{}\"", TRACEPARENT));
+        assert_eq!(serde_json::from_str::<TraceContext>(&json).unwrap(), trace);
+    }
+}
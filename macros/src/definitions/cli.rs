@@ -34,6 +34,13 @@ impl ToTokens for Cli {
                 let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
                 tracing_subscriber::fmt().with_writer(non_blocking).init();
 
+                // Every request the command makes is part of this trace, in every provider's logs
+                ::registry::tracing::info!(
+                    "Running {:?} as trace {}",
+                    std::env::args().skip(1).collect::<Vec<_>>(),
+                    ::registry::cliff::trace::current()
+                );
+
                 #command_name::parse().run();
             }
         };
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::convert::{From, TryFrom};
 
 use syn::punctuated::Punctuated;
-use syn::{Block, Expr, Ident, Pat, Token, Type};
+use syn::{parse_quote, Block, Expr, Ident, Index, Pat, Token, Type};
 
 #[derive(Clone, Debug)]
 pub enum ServerInterface {
@@ -32,6 +32,8 @@ pub struct CaseFieldValue {
 pub struct CaseDeclaration {
     pub(crate) name: Ident,
     pub(crate) fields: Vec<CaseField>,
+    /// Requests carry their caller's trace, declared last so positional params can leave it out
+    pub(crate) traced: bool,
 }
 
 impl From<&RequestCase> for CaseDeclaration {
@@ -39,6 +41,7 @@ impl From<&RequestCase> for CaseDeclaration {
         CaseDeclaration {
             name: case.ident.clone(),
             fields: case.fields.to_vec(),
+            traced: true,
         }
     }
 }
@@ -53,6 +56,7 @@ impl TryFrom<&ResponseCase> for CaseDeclaration {
             Empty { name } => CaseDeclaration {
                 name: name.clone(),
                 fields: vec![],
+                traced: false,
             },
             Typed { name, types, build } => CaseDeclaration {
                 name: name.clone(),
@@ -64,10 +68,12 @@ impl TryFrom<&ResponseCase> for CaseDeclaration {
                         ty: ty.clone(),
                     })
                     .collect(),
+                traced: false,
             },
             Structured { name: _, build: _ } => {
                 return Err("Can't convert Structured variant into Case Declaration".into());
             }
+            Stream { frame, .. } => return CaseDeclaration::try_from(frame.as_ref()),
         };
 
         Ok(declaration)
@@ -88,6 +94,12 @@ pub enum ResponseCase {
         types: Vec<Type>,
         build: Punctuated<CaseFieldValue, Token![,]>,
     },
+    /// A `frame` for every item of `items`, a stream of `Result<_, RpcError>`, then an `End`
+    Stream {
+        item: Box<Pat>,
+        items: Box<Expr>,
+        frame: Box<ResponseCase>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -102,6 +114,21 @@ pub enum Response {
     Conditional { cases: Vec<ConditionalResponse> },
 }
 
+impl Response {
+    pub(crate) fn cases(&self) -> Vec<ResponseCase> {
+        match self {
+            Response::Base { case } => vec![case.clone()],
+            Response::Conditional { cases } => cases.iter().map(|c| c.response.clone()).collect(),
+        }
+    }
+
+    pub(crate) fn streams(&self) -> bool {
+        self.cases()
+            .iter()
+            .any(|case| matches!(case, ResponseCase::Stream { .. }))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RequestHandler {
     pub(crate) request_case: RequestCase,
@@ -109,10 +136,23 @@ pub struct RequestHandler {
     pub(crate) response: Response,
 }
 
+#[derive(Clone, Debug)]
+pub struct NotificationDeclaration {
+    pub(crate) name: Ident,
+    pub(crate) fields: Vec<CaseField>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ServerEntry {
+    Handler(RequestHandler),
+    Notification(NotificationDeclaration),
+}
+
 #[derive(Clone, Debug)]
 pub struct ServerMessage {
     pub(crate) interface_name: Ident,
     pub(crate) handlers: Vec<RequestHandler>,
+    pub(crate) notifications: Vec<NotificationDeclaration>,
 }
 
 #[derive(Clone, Debug)]
@@ -129,6 +169,8 @@ pub struct Client {
 
     pub(crate) actions: Vec<ClientAction>,
     pub(crate) response_mapping: Vec<ResponseMapping>,
+    pub(crate) stream_mapping: Vec<ResponseMapping>,
+    pub(crate) notifications: Vec<Ident>,
 }
 
 impl Client {
@@ -152,12 +194,31 @@ impl Client {
             self.request_type.span(),
         )
     }
+
+    /// The item types of the actions answered with a stream, each with a map of its own
+    pub(crate) fn get_stream_types(&self) -> Vec<Type> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action.response.clone() {
+                Some(ClientResponse::Stream(StreamedResponse { ty })) => Some(ty),
+                _ => None,
+            })
+            .fold(vec![], |mut types, ty| {
+                if !types.contains(&ty) {
+                    types.push(ty);
+                }
+
+                types
+            })
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum ClientFields {
     Actions(Vec<ClientAction>),
     ResponseMapping(Vec<ResponseMapping>),
+    StreamMapping(Vec<ResponseMapping>),
+    Notifications(Vec<Ident>),
 }
 
 #[derive(Clone, Debug)]
@@ -176,6 +237,9 @@ impl From<&ClientAction> for ActionDeclaration {
             .clone()
             .map(|res| match res {
                 ClientResponse::Wait(res) => res.ty,
+                ClientResponse::Stream(StreamedResponse { ty }) => {
+                    Some(parse_quote! { ::cliff::client::Streaming<#ty> })
+                }
             })
             .flatten();
 
@@ -204,6 +268,7 @@ impl FutureDescriptor {
                     action.action_type.name.clone(),
                     action.response.clone().and_then(|res| match res {
                         ClientResponse::Wait(WaitResponse { ty }) => Some(ty),
+                        ClientResponse::Stream(_) => None,
                     }),
                 )
             })
@@ -317,11 +382,45 @@ impl FutureResponseMapping {
     }
 }
 
+/// Sends the items a frame maps to down the streams waiting for them
+#[derive(Clone, Debug)]
+pub struct StreamResponseMapping {
+    pub(crate) mapping_case: ResponseMappingCase,
+    pub(crate) indexed_mappings: Vec<(Index, TypedActionMapping)>,
+}
+
+impl StreamResponseMapping {
+    pub(crate) fn wrap_mapping(
+        mapping: &ResponseMapping,
+        stream_types: &[Type],
+    ) -> StreamResponseMapping {
+        StreamResponseMapping {
+            mapping_case: mapping.response_case.clone(),
+            indexed_mappings: stream_types
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, ty)| {
+                    mapping
+                        .action_mapping
+                        .iter()
+                        .find(|action_mapping| action_mapping.get_type().as_ref() == Some(ty))
+                        .map(|action_mapping| (Index::from(idx), action_mapping.clone()))
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HandlerDeclaration {
     pub(crate) client_name: Ident,
     pub(crate) request_name: Ident,
+    pub(crate) response_name: Ident,
     pub(crate) future_mapping: FutureRequestMapping,
+    /// Which of the client's streams the action's items go to, if it's answered with one
+    pub(crate) stream_index: Option<Index>,
+    /// What the action resolves to when its request fails before it's answered
+    pub(crate) error_mapping: Option<(ResponseMappingCase, TypedActionMapping)>,
 
     pub(crate) action: ClientAction,
 }
@@ -332,7 +431,9 @@ impl HandlerDeclaration {
         descriptors: &[FutureDescriptor],
     ) -> Vec<HandlerDeclaration> {
         let request_name = client.get_request_type_name();
+        let response_name = client.get_response_type_name();
         let client_name = client.get_client_type_name();
+        let stream_types = client.get_stream_types();
 
         client
             .actions
@@ -340,11 +441,27 @@ impl HandlerDeclaration {
             .map(|action| {
                 let future_mapping =
                     FutureRequestMapping::get_mapping(&action.action_type.name, descriptors);
+                let stream_index = match action.response.clone() {
+                    Some(ClientResponse::Stream(StreamedResponse { ty })) => stream_types
+                        .iter()
+                        .position(|stream_type| *stream_type == ty)
+                        .map(Index::from),
+                    _ => None,
+                };
+                let error_mapping = match action.response.clone() {
+                    Some(ClientResponse::Wait(WaitResponse { ty: Some(ty) })) => {
+                        ResponseMapping::error_mapping(&client.response_mapping, &ty)
+                    }
+                    _ => None,
+                };
 
                 HandlerDeclaration {
                     client_name: client_name.clone(),
                     request_name: request_name.clone(),
+                    response_name: response_name.clone(),
                     future_mapping,
+                    stream_index,
+                    error_mapping,
                     action: action.clone(),
                 }
             })
@@ -370,6 +487,7 @@ pub enum ActionMapping {
 #[derive(Clone, Debug)]
 pub enum ClientResponse {
     Wait(WaitResponse),
+    Stream(StreamedResponse),
 }
 
 #[derive(Clone, Debug)]
@@ -377,6 +495,12 @@ pub struct WaitResponse {
     pub(crate) ty: Option<Type>,
 }
 
+/// Actions answered with a stream resolve to a `cliff::client::Streaming` of `ty`
+#[derive(Clone, Debug)]
+pub struct StreamedResponse {
+    pub(crate) ty: Type,
+}
+
 #[derive(Clone, Debug)]
 pub enum TypedActionMapping {
     UnitMapping { block: Option<Block> },
@@ -411,8 +535,36 @@ pub enum ResponseMappingCase {
     },
 }
 
+impl ResponseMappingCase {
+    fn name(&self) -> &Ident {
+        match self {
+            ResponseMappingCase::Empty { name } => name,
+            ResponseMappingCase::Structured { name, build: _ } => name,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ResponseMapping {
     pub(crate) response_case: ResponseMappingCase,
     pub(crate) action_mapping: Vec<TypedActionMapping>,
 }
+
+impl ResponseMapping {
+    /// How a failed request resolves for actions waiting on `ty`
+    pub(crate) fn error_mapping(
+        mappings: &[ResponseMapping],
+        ty: &Type,
+    ) -> Option<(ResponseMappingCase, TypedActionMapping)> {
+        mappings
+            .iter()
+            .filter(|mapping| mapping.response_case.name() == "Error")
+            .find_map(|mapping| {
+                mapping
+                    .action_mapping
+                    .iter()
+                    .find(|action_mapping| action_mapping.get_type().as_ref() == Some(ty))
+                    .map(|action_mapping| (mapping.response_case.clone(), action_mapping.clone()))
+            })
+    }
+}
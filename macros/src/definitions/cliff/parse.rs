@@ -74,6 +74,10 @@ impl Parse for CaseFieldValue {
 
 impl Parse for ResponseCase {
     fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(Token![for]) {
+            return Self::parse_stream(input);
+        }
+
         let name = input.parse()?;
         let lookahead = input.lookahead1();
 
@@ -110,6 +114,21 @@ impl ResponseCase {
 
         Punctuated::parse_terminated(&content)
     }
+
+    // `for item in items => Frame`
+    fn parse_stream(input: ParseStream) -> Result<Self> {
+        let _: Token![for] = input.parse()?;
+        let item = Box::new(input.parse()?);
+        let _: Token![in] = input.parse()?;
+        let items = Box::new(input.parse()?);
+        let _: Token![=>] = input.parse()?;
+
+        Ok(ResponseCase::Stream {
+            item,
+            items,
+            frame: Box::new(input.parse()?),
+        })
+    }
 }
 
 impl Parse for ConditionalResponse {
@@ -135,7 +154,7 @@ impl Parse for Response {
         let lookahead = input.lookahead1();
         let response = if lookahead.peek(token::Bracket) {
             Self::parse_conditional(input)?
-        } else if lookahead.peek(Ident) {
+        } else if lookahead.peek(Ident) || lookahead.peek(Token![for]) {
             Self::parse_base(input)?
         } else {
             return Err(lookahead.error());
@@ -190,26 +209,74 @@ impl Parse for RequestHandler {
     }
 }
 
-impl Parse for ServerMessage {
+mod server_keywords {
+    syn::custom_keyword!(notify);
+}
+
+impl Parse for NotificationDeclaration {
     fn parse(input: ParseStream) -> Result<Self> {
-        let interface_name = input.parse()?;
+        let _: server_keywords::notify = input.parse()?;
+        let name = input.parse()?;
 
+        let fields = if input.peek(token::Brace) {
+            let content;
+            let _ = braced!(content in input);
+            Punctuated::<CaseField, Token![,]>::parse_terminated(&content)?
+                .iter()
+                .cloned()
+                .collect()
+        } else {
+            vec![]
+        };
+
+        Ok(NotificationDeclaration { name, fields })
+    }
+}
+
+impl Parse for ServerEntry {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(server_keywords::notify) {
+            Ok(ServerEntry::Notification(input.parse()?))
+        } else {
+            Ok(ServerEntry::Handler(input.parse()?))
+        }
+    }
+}
+
+impl ServerMessage {
+    fn parse_entries(interface_name: Ident, input: ParseStream) -> Result<Self> {
         let content;
         let _: token::Bracket = bracketed!(content in input);
 
-        let handlers = Punctuated::<RequestHandler, Token![,]>::parse_terminated(&content)
+        let mut handlers = vec![];
+        let mut notifications = vec![];
+        for entry in Punctuated::<ServerEntry, Token![,]>::parse_terminated(&content)
             .iter()
             .flatten()
             .cloned()
-            .collect();
+        {
+            match entry {
+                ServerEntry::Handler(handler) => handlers.push(handler),
+                ServerEntry::Notification(notification) => notifications.push(notification),
+            }
+        }
 
         Ok(ServerMessage {
             interface_name,
             handlers,
+            notifications,
         })
     }
 }
 
+impl Parse for ServerMessage {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let interface_name = input.parse()?;
+
+        ServerMessage::parse_entries(interface_name, input)
+    }
+}
+
 impl Parse for Router {
     fn parse(input: ParseStream) -> Result<Self> {
         let router_type: Ident = input.parse()?;
@@ -219,18 +286,7 @@ impl Parse for Router {
             let _: Token![;] = input.parse()?;
             input.parse()?
         } else if lookahead.peek(token::Bracket) {
-            let content;
-            let _: token::Bracket = bracketed!(content in input);
-            let handlers = Punctuated::<RequestHandler, Token![,]>::parse_terminated(&content)
-                .iter()
-                .flatten()
-                .cloned()
-                .collect();
-
-            ServerInterface::Single(ServerMessage {
-                interface_name: router_type.clone(),
-                handlers,
-            })
+            ServerInterface::Single(ServerMessage::parse_entries(router_type.clone(), input)?)
         } else {
             return Err(lookahead.error());
         };
@@ -247,9 +303,12 @@ mod client_keywords {
     syn::custom_keyword!(named);
     syn::custom_keyword!(into);
     syn::custom_keyword!(wait);
+    syn::custom_keyword!(stream);
     syn::custom_keyword!(on);
     syn::custom_keyword!(actions);
     syn::custom_keyword!(response_mapping);
+    syn::custom_keyword!(stream_mapping);
+    syn::custom_keyword!(notifications);
 }
 
 impl Parse for Client {
@@ -296,12 +355,45 @@ impl Parse for Client {
             _ => vec![],
         };
 
+        // Requests can fail before they're answered, and the action has to resolve to something
+        for action in actions.iter() {
+            if let Some(ClientResponse::Wait(WaitResponse { ty: Some(ty) })) = &action.response {
+                if ResponseMapping::error_mapping(&response_mapping, ty).is_none() {
+                    return Err(syn::Error::new(
+                        action.action_type.name.span(),
+                        format!(
+                            "Missing 'Error' mapping for '{}', which failed requests resolve to",
+                            quote::quote! { #ty }
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let stream_mapping = interface
+            .iter()
+            .find_map(|field| match field {
+                ClientFields::StreamMapping(mapping) => Some(mapping.to_vec()),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let notifications = match interface.iter().find(|field| match field {
+            ClientFields::Notifications(_) => true,
+            _ => false,
+        }) {
+            Some(ClientFields::Notifications(notifications)) => notifications.to_vec(),
+            _ => vec![],
+        };
+
         Ok(Client {
             request_type,
             client_name,
 
             actions,
             response_mapping,
+            stream_mapping,
+            notifications,
         })
     }
 }
@@ -333,6 +425,30 @@ impl Parse for ClientFields {
                 .collect();
 
             ClientFields::ResponseMapping(mappings)
+        } else if lookahead.peek(client_keywords::stream_mapping) {
+            let _: client_keywords::stream_mapping = input.parse()?;
+            let _: Token![=>] = input.parse()?;
+
+            let content;
+            let _ = bracketed!(content in input);
+            let mappings = Punctuated::<ResponseMapping, Token![,]>::parse_terminated(&content)?
+                .iter()
+                .cloned()
+                .collect();
+
+            ClientFields::StreamMapping(mappings)
+        } else if lookahead.peek(client_keywords::notifications) {
+            let _: client_keywords::notifications = input.parse()?;
+            let _: Token![=>] = input.parse()?;
+
+            let content;
+            let _ = bracketed!(content in input);
+            let notifications = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?
+                .iter()
+                .cloned()
+                .collect();
+
+            ClientFields::Notifications(notifications)
         } else {
             return Err(lookahead.error());
         };
@@ -354,11 +470,12 @@ impl Parse for ClientAction {
         };
 
         let lookahead = input.lookahead1();
-        let response = if lookahead.peek(client_keywords::wait) {
-            Some(input.parse()?)
-        } else {
-            None
-        };
+        let response =
+            if lookahead.peek(client_keywords::wait) || lookahead.peek(client_keywords::stream) {
+                Some(input.parse()?)
+            } else {
+                None
+            };
 
         Ok(ClientAction {
             action_type,
@@ -382,6 +499,7 @@ impl Parse for ActionType {
                 .cloned()
                 .collect()
         } else if lookahead.peek(client_keywords::wait)
+            || lookahead.peek(client_keywords::stream)
             || lookahead.peek(client_keywords::into)
             || lookahead.peek(Token![,])
         {
@@ -475,6 +593,9 @@ impl Parse for ClientResponse {
         let case = if lookahead.peek(client_keywords::wait) {
             let _: client_keywords::wait = input.parse()?;
             ClientResponse::Wait(input.parse()?)
+        } else if lookahead.peek(client_keywords::stream) {
+            let _: client_keywords::stream = input.parse()?;
+            ClientResponse::Stream(StreamedResponse { ty: input.parse()? })
         } else {
             return Err(lookahead.error());
         };
@@ -17,10 +17,10 @@ impl ToTokens for RequestCase {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let RequestCase { ident, fields } = self;
         let stream = if fields.is_empty() {
-            quote! { #ident { rqs_id } }
+            quote! { #ident { rqs_id, .. } }
         } else {
             let field_names: Vec<Ident> = fields.iter().map(|f| f.name.clone()).collect();
-            quote! { #ident { rqs_id, #(#field_names),* } }
+            quote! { #ident { rqs_id, #(#field_names,)* .. } }
         };
 
         tokens.append_all(stream);
@@ -55,22 +55,40 @@ impl ToTokens for CaseFieldMapping {
 
 impl ToTokens for CaseDeclaration {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let CaseDeclaration { name, fields } = self;
-        let stream = if !fields.is_empty() {
+        let CaseDeclaration {
+            name,
+            fields,
+            traced,
+        } = self;
+        let trace = if *traced {
             quote! {
-                #name {
-                    rqs_id: u32,
-                    #(#fields),*
-                }
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                trace: Option<::cliff::codec::TraceContext>,
             }
         } else {
-            quote! { #name { rqs_id: u32 } }
+            quote! {}
+        };
+
+        let stream = quote! {
+            #name {
+                rqs_id: ::cliff::codec::Id,
+                #(#fields,)*
+                #trace
+            }
         };
 
         tokens.append_all(stream);
     }
 }
 
+impl ToTokens for NotificationDeclaration {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let NotificationDeclaration { name, fields } = self;
+
+        tokens.append_all(quote! { #name { #(#fields),* } });
+    }
+}
+
 impl ToTokens for ResponseCase {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         use ResponseCase::*;
@@ -83,6 +101,20 @@ impl ToTokens for ResponseCase {
                 types: _,
                 build,
             } => quote! { #name { rqs_id, #build } },
+            Stream { item, items, frame } => quote! {
+                {
+                    let frame_id = rqs_id.clone();
+                    Streamed(::cliff::server::ResponseStream::new(
+                        rqs_id.clone(),
+                        #items,
+                        move |#item| {
+                            let rqs_id = frame_id.clone();
+                            #frame
+                        },
+                        End { rqs_id },
+                    ))
+                }
+            },
         };
 
         tokens.append_all(stream)
@@ -168,6 +200,8 @@ impl ToTokens for Client {
             client_name: _,
             actions,
             response_mapping,
+            stream_mapping,
+            notifications,
         } = self;
 
         let client = self.get_client_type_name();
@@ -189,39 +223,162 @@ impl ToTokens for Client {
             .map(|mapping| FutureResponseMapping::wrap_mapping(mapping, &future_descriptors))
             .collect();
 
-        let stream_handler = if response_mapping.is_empty() {
+        let stream_types = self.get_stream_types();
+        let stream_indices: Vec<Index> = (0..stream_types.len()).map(Index::from).collect();
+        let stream_maps: Vec<TokenStream> = stream_types
+            .iter()
+            .map(|_| quote! { ::std::collections::HashMap::new() })
+            .collect();
+        let stream_mapping: Vec<StreamResponseMapping> = stream_mapping
+            .iter()
+            .map(|mapping| StreamResponseMapping::wrap_mapping(mapping, &stream_types))
+            .collect();
+
+        let (pending_ids, drop_request) = Client::get_pending(&future_descriptors);
+        // Streams are closed by the frame that ends them, whether that's `End` or an error
+        let (streams_declaration, streams_init, pending_streams, stream_arms) = if stream_types
+            .is_empty()
+        {
+            (quote! {}, quote! {}, quote! {}, quote! {})
+        } else {
+            (
+                quote! {
+                    streams: (#(
+                        ::std::collections::HashMap<
+                            ::cliff::codec::Id,
+                            ::cliff::futures::channel::mpsc::UnboundedSender<
+                                Result<#stream_types, ::cliff::failure::Error>
+                            >
+                        >,
+                    )*),
+                },
+                quote! { streams: (#(#stream_maps,)*), },
+                quote! {
+                    let pending: Vec<::cliff::codec::Id> = pending
+                        .into_iter()
+                        #(.chain(self.streams.#stream_indices.keys().cloned()))*
+                        .collect();
+                },
+                quote! {
+                    Ok(Error { rqs_id, error }) if #(self.streams.#stream_indices.contains_key(&rqs_id))||* => {
+                      #(if let Some(tx) = self.streams.#stream_indices.remove(&rqs_id) {
+                        let _ = tx.unbounded_send(Err(error.clone().into()));
+                      })*
+                    }
+                    Ok(End { rqs_id }) => {
+                      #(self.streams.#stream_indices.remove(&rqs_id);)*
+                    }
+                    #(#stream_mapping)*
+                },
+            )
+        };
+        // Errors without a mapping of their own still fail the request they answer
+        let unmapped_error = if future_descriptors.is_empty() {
+            quote! {}
+        } else {
+            quote! {
+                #[allow(unreachable_patterns)]
+                Ok(Error { rqs_id, .. }) => { #drop_request }
+            }
+        };
+
+        // Notifications go out to whoever registered with `Listen`
+        let (listeners_declaration, listeners_init, listen_handler) = if notifications.is_empty() {
+            (quote! {}, quote! {}, quote! {})
+        } else {
+            (
+                quote! { listeners: Vec<::cliff::actix::Recipient<#response_name>>, },
+                quote! { listeners: Vec::new(), },
+                quote! {
+                    impl Handler<::cliff::client::Listen<#response_name>> for #client {
+                        type Result = ();
+
+                        fn handle(&mut self, msg: ::cliff::client::Listen<#response_name>, _ctx: &mut Self::Context) -> Self::Result {
+                            self.listeners.push(msg.0);
+                        }
+                    }
+                },
+            )
+        };
+
+        let listens =
+            !response_mapping.is_empty() || !stream_types.is_empty() || !notifications.is_empty();
+
+        let stream_handler = if !listens {
             quote! {}
         } else {
             quote! {
                 impl StreamHandler<::core::result::Result<#response_name, ::cliff::failure::Error>> for #client {
-                  fn handle(&mut self, item: Result<#response_name, ::cliff::failure::Error>, _ctx: &mut Self::Context) {
+                  fn handle(&mut self, item: Result<#response_name, ::cliff::failure::Error>, ctx: &mut Self::Context) {
                     use #response_name::*;
 
                     match item {
+                      // The server couldn't tell which request it failed to read
+                      Ok(Error { rqs_id: ::cliff::codec::Id::Null, error }) => self.fail_pending(error, ctx),
+                      #(Ok(msg @ #notifications { .. }) => ::cliff::client::broadcast(&mut self.listeners, msg),)*
+                      #stream_arms
                       #(#response_mapping)*
+                      #unmapped_error
+                      Err(e) => match e.downcast::<::cliff::codec::DecodeError>() {
+                        Ok(::cliff::codec::DecodeError { id: Some(rqs_id), error }) if rqs_id != ::cliff::codec::Id::Null => {
+                          self.fail_request(rqs_id, error, ctx)
+                        }
+                        Ok(::cliff::codec::DecodeError { error, .. }) => self.fail_pending(error, ctx),
+                        Err(e) => self.fail_pending(
+                          ::cliff::codec::RpcError::from_failure(::cliff::codec::ErrorCode::InternalError, &e),
+                          ctx,
+                        ),
+                      },
                       _ => {}
                     }
                   }
+
+                  fn finished(&mut self, ctx: &mut Self::Context) {
+                    self.fail_pending(
+                      ::cliff::codec::RpcError::new(::cliff::codec::ErrorCode::InternalError, "Connection closed"),
+                      ctx,
+                    );
+                    ctx.stop();
+                  }
+                }
+
+                impl #client {
+                  fn fail_request(&mut self, rqs_id: ::cliff::codec::Id, error: ::cliff::codec::RpcError, ctx: &mut Context<Self>) {
+                    StreamHandler::handle(self, Ok(#response_name::Error { rqs_id: rqs_id.clone(), error }), ctx);
+
+                    // Unless the `Error` mapping answered it, dropping the sender fails it for the caller
+                    #drop_request
+                  }
+
+                  fn fail_pending(&mut self, error: ::cliff::codec::RpcError, ctx: &mut Context<Self>) {
+                    let pending: Vec<::cliff::codec::Id> = #pending_ids;
+                    #pending_streams
+                    for rqs_id in pending {
+                      self.fail_request(rqs_id, error.clone(), ctx);
+                    }
+                  }
                 }
             }
         };
 
-        let create = if response_mapping.is_empty() {
+        let create = if !listens {
             quote! {
                 let addr = #client {
                     next_id: ::cliff::rand::random(),
-                    writer
+                    writer,
                 }.start();
             }
         } else {
             quote! {
                 let addr = #client::create(|ctx| {
-                  #client::listen(r, ctx);
+                  #client::listen(r, negotiated, protocol.max_frame_size(), capture, ctx);
 
                   #client {
                     next_id: ::cliff::rand::random(),
                     writer,
                     #futures_init
+                    #streams_init
+                    #listeners_init
                   }
                 });
             }
@@ -232,6 +389,8 @@ impl ToTokens for Client {
                 next_id: u32,
                 writer: ::cliff::actix::Addr<::cliff::client::WriteInterface<#request_name>>,
                 #futures_declaration
+                #streams_declaration
+                #listeners_declaration
             }
 
             impl Actor for #client {
@@ -242,18 +401,45 @@ impl ToTokens for Client {
 
             #(#handler_declarations)*
 
+            impl Handler<::cliff::client::BeginBatch> for #client {
+                type Result = ();
+
+                fn handle(&mut self, msg: ::cliff::client::BeginBatch, _ctx: &mut Self::Context) -> Self::Result {
+                    self.writer.do_send(msg);
+                }
+            }
+
+            impl Handler<::cliff::client::FlushBatch> for #client {
+                type Result = ();
+
+                fn handle(&mut self, msg: ::cliff::client::FlushBatch, _ctx: &mut Self::Context) -> Self::Result {
+                    self.writer.do_send(msg);
+                }
+            }
+
             #stream_handler
 
+            #listen_handler
+
             #[::cliff::async_trait::async_trait]
             impl ::cliff::client::IpcClient for #client {
-              async fn connect(path: &str) -> core::result::Result<Addr<Self>, ::failure::Error> {
+              async fn connect(address: &str) -> core::result::Result<Addr<Self>, ::failure::Error> {
+                Self::connect_with(address, ::cliff::handshake::Protocol::default()).await
+              }
+
+              async fn connect_with(
+                  address: &str,
+                  protocol: ::cliff::handshake::Protocol,
+              ) -> core::result::Result<Addr<Self>, ::failure::Error> {
                 use ::cliff::failure::ResultExt;
                 use ::cliff::client::Delegate;
 
-                let stream = ::cliff::tokio::net::UnixStream::connect(path).await?;
+                let mut stream = ::cliff::transport::Connection::connect(address).await?;
+                let negotiated = ::cliff::handshake::initiate(&mut stream, &protocol).await?;
                 let (r, w) = ::cliff::tokio::io::split(stream);
 
-                let writer = ::cliff::client::WriteInterface::<#request_name>::attach(w).await?;
+                let capture = ::cliff::capture::start(&protocol, ::cliff::capture::Side::Client, negotiated);
+                let writer = ::cliff::client::WriteInterface::<#request_name>::attach(w, negotiated, capture.clone()).await?;
 
                 #create
 
@@ -267,6 +453,24 @@ impl ToTokens for Client {
 }
 
 impl Client {
+    fn get_pending(future_descriptors: &[FutureDescriptor]) -> (TokenStream, TokenStream) {
+        if future_descriptors.is_empty() {
+            (quote! { Vec::new() }, quote! {})
+        } else if future_descriptors.len() == 1 {
+            (
+                quote! { self.futures.keys().cloned().collect() },
+                quote! { self.futures.remove(&rqs_id); },
+            )
+        } else {
+            let indices: Vec<Index> = (0..future_descriptors.len()).map(Index::from).collect();
+
+            (
+                quote! { Vec::new().into_iter()#(.chain(self.futures.#indices.keys().cloned()))*.collect() },
+                quote! { #(self.futures.#indices.remove(&rqs_id);)* },
+            )
+        }
+    }
+
     fn get_futures(future_descriptors: &[FutureDescriptor]) -> (TokenStream, TokenStream) {
         if future_descriptors.is_empty() {
             (quote! {}, quote! {})
@@ -274,10 +478,10 @@ impl Client {
             let descriptor = future_descriptors[0].clone();
             (
                 quote! {
-                    futures: #descriptor
+                    futures: #descriptor,
                 },
                 quote! {
-                    futures: ::std::collections::HashMap::new()
+                    futures: ::std::collections::HashMap::new(),
                 },
             )
         } else {
@@ -289,10 +493,10 @@ impl Client {
 
             (
                 quote! {
-                    futures: (#(#future_descriptors),*)
+                    futures: (#(#future_descriptors),*),
                 },
                 quote! {
-                    futures: (#(#hashes),*)
+                    futures: (#(#hashes),*),
                 },
             )
         }
@@ -355,7 +559,7 @@ impl ToTokens for FutureDescriptor {
         let def = quote! { () };
         let result_type = self.result_type.clone().map_or(def, |ty| quote! { #ty });
 
-        let stream = quote! { std::collections::HashMap<u32, ::cliff::tokio::sync::oneshot::Sender<#result_type>> };
+        let stream = quote! { std::collections::HashMap<::cliff::codec::Id, ::cliff::tokio::sync::oneshot::Sender<#result_type>> };
 
         tokens.append_all(stream);
     }
@@ -366,7 +570,10 @@ impl ToTokens for HandlerDeclaration {
         let HandlerDeclaration {
             client_name,
             request_name,
+            response_name,
             future_mapping,
+            stream_index,
+            error_mapping,
             action:
                 ClientAction {
                     action_type,
@@ -377,28 +584,79 @@ impl ToTokens for HandlerDeclaration {
 
         let action_name = action_type.name.clone();
 
-        let response_type = response
-            .clone()
-            .and_then(|response| match response {
-                ClientResponse::Wait(WaitResponse { ty: Some(ty) }) => Some(quote! { #ty }),
-                _ => None,
-            })
-            .unwrap_or(quote! { () });
+        let response_ty = response.clone().and_then(|response| match response {
+            ClientResponse::Wait(WaitResponse { ty: Some(ty) }) => Some(quote! { #ty }),
+            ClientResponse::Stream(StreamedResponse { ty }) => {
+                Some(quote! { ::cliff::client::Streaming<#ty> })
+            }
+            _ => None,
+        });
+        let returns_unit = response_ty.is_none();
+        let response_type = response_ty.unwrap_or(quote! { () });
 
         let request_mapping = if let Some(mapping) = mapped_request {
             quote! { #mapping }
         } else if action_type.fields.is_empty() {
             let name = action_type.name.clone();
-            quote! { #name { rqs_id } }
+            quote! { #name { rqs_id, trace } }
         } else {
             let ActionType { name, fields } = action_type;
             let fields: Vec<Ident> = fields.iter().map(|f| f.name.clone()).collect();
-            quote! { #name { rqs_id, #(#fields),* } }
+            quote! { #name { rqs_id, #(#fields,)* trace } }
+        };
+
+        // Items go down the stream as their frames arrive, until the one that ends it
+        let stream_mapping = match stream_index {
+            Some(index) => quote! {
+              let (tx, rx) = ::cliff::futures::channel::mpsc::unbounded();
+
+              self.streams.#index.insert(rqs_id.clone(), tx);
+            },
+            None => quote! {},
+        };
+
+        let response_future = match future_mapping {
+            _ if stream_index.is_some() => quote! { Box::pin(async move { rx }) },
+            FutureRequestMapping::None => quote! { Box::pin(async {}) },
+            // There's nothing to hand back when waiting for `()`, so a failed request just resolves
+            _ if returns_unit => quote! { Box::pin(async move { let _ = rx.await; }) },
+            // Otherwise it resolves the way an error from the router would
+            _ => {
+                let (mapping_case, action_mapping) = error_mapping
+                    .clone()
+                    .expect("Typed waits are checked for an error mapping when parsed");
+                quote! {
+                  Box::pin(async move {
+                    match rx.await {
+                      Ok(result) => result,
+                      Err(_) => {
+                        let error = ::cliff::codec::RpcError::new(
+                          ::cliff::codec::ErrorCode::InternalError,
+                          "Request failed before a response arrived",
+                        );
+
+                        use #response_name::*;
+
+                        #[allow(unreachable_patterns, unused_variables)]
+                        match (Error { rqs_id: failed, error }) {
+                          #mapping_case => #action_mapping,
+                          _ => unreachable!("Error mappings match every error"),
+                        }
+                      }
+                    }
+                  })
+                }
+            }
         };
 
-        let then_mapping = match future_mapping {
-            FutureRequestMapping::None => quote! { .then(|_| async {}) },
-            _ => quote! { .then(|_| async move { rx.await.unwrap() }) },
+        // The request takes the id with it, so one is kept back for resolving it as failed
+        let failed_id = if returns_unit
+            || stream_index.is_some()
+            || matches!(future_mapping, FutureRequestMapping::None)
+        {
+            quote! {}
+        } else {
+            quote! { let failed = rqs_id.clone(); }
         };
 
         let stream = quote! {
@@ -406,22 +664,32 @@ impl ToTokens for HandlerDeclaration {
             type Result = ResponseFuture<#response_type>;
 
             fn handle(&mut self, msg: #action_name, _ctx: &mut Self::Context) -> Self::Result {
-              use ::cliff::futures::FutureExt;
-
               #action_type;
-              let rqs_id = self.next_id;
-              self.next_id += 1;
+              let rqs_id = ::cliff::codec::Id::from(self.next_id);
+              self.next_id = self.next_id.wrapping_add(1);
+              let trace = Some(::cliff::trace::current());
 
               #future_mapping
+              #stream_mapping
+              #failed_id
 
               {
                   use #request_name::*;
-                  Box::pin(
-                      self.writer
-                          .send(::cliff::client::InterfaceRequest(#request_mapping))
-                          #then_mapping
-                  )
+                  // Queued right away so requests keep their order within a batch
+                  self.writer.do_send(::cliff::client::InterfaceRequest(#request_mapping));
               }
+
+              #response_future
+            }
+          }
+
+          impl Handler<::cliff::trace::Traced<#action_name>> for #client_name {
+            type Result = ResponseFuture<#response_type>;
+
+            fn handle(&mut self, traced: ::cliff::trace::Traced<#action_name>, ctx: &mut Self::Context) -> Self::Result {
+              let _trace = ::cliff::trace::enter(traced.trace);
+
+              <Self as Handler<#action_name>>::handle(self, traced.message, ctx)
             }
           }
         };
@@ -452,7 +720,9 @@ impl ToTokens for ActionMapping {
         use ActionMapping::*;
 
         let stream = match self {
-            BaseMapping { name, field_values } => quote! { #name { rqs_id, #(#field_values),* } },
+            BaseMapping { name, field_values } => {
+                quote! { #name { rqs_id, #(#field_values,)* trace } }
+            }
             BlockMapping(block) => quote! { #block },
         };
 
@@ -486,8 +756,11 @@ impl ToTokens for ResponseMappingCase {
         use ResponseMappingCase::*;
 
         let stream = match self {
-            Empty { name } => quote! { #name { rqs_id } },
-            Structured { name, build } => quote! { #name { rqs_id, #build } },
+            Empty { name } => quote! { #name { rqs_id, .. } },
+            Structured { name, build } => {
+                let fields = build.iter();
+                quote! { #name { rqs_id, #(#fields,)* .. } }
+            }
         };
 
         tokens.append_all(stream)
@@ -503,7 +776,7 @@ impl ToTokens for FutureResponseMapping {
             } => quote! {
               Ok(#mapping_case) => {
                 if let Some(tx) = self.futures.remove(&rqs_id) {
-                  tx.send(#action_mapping).unwrap();
+                  let _ = tx.send(#action_mapping);
                 }
               }
             },
@@ -521,6 +794,26 @@ impl ToTokens for FutureResponseMapping {
     }
 }
 
+impl ToTokens for StreamResponseMapping {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let StreamResponseMapping {
+            mapping_case,
+            indexed_mappings,
+        } = self;
+        let (indices, action_mappings): (Vec<_>, Vec<_>) = indexed_mappings.iter().cloned().unzip();
+
+        let stream = quote! {
+          Ok(#mapping_case) => {
+            #(if let Some(tx) = self.streams.#indices.get(&rqs_id) {
+              let _ = tx.unbounded_send(Ok(#action_mappings));
+            })*
+          }
+        };
+
+        tokens.append_all(stream)
+    }
+}
+
 impl ToTokens for TypedActionMapping {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let stream = match self {
@@ -544,7 +837,7 @@ impl ToTokens for IndexMapping {
 
         let stream = quote! {
             if let Some(tx) = self.futures.#index.remove(&rqs_id) {
-              tx.send(#action_mapping).unwrap();
+              let _ = tx.send(#action_mapping);
             }
         };
 
@@ -4,10 +4,10 @@ use itertools::Itertools;
 
 use quote::quote;
 
-use syn::Ident;
+use syn::{parse_quote, Ident};
 
 use super::nodes::{
-    CaseDeclaration, RequestHandler, Response, Router, ServerInterface, ServerMessage,
+    CaseDeclaration, CaseField, RequestHandler, Router, ServerInterface, ServerMessage,
 };
 
 pub fn build_router(router: Router) -> proc_macro2::TokenStream {
@@ -44,8 +44,40 @@ fn build_server_message(router_type: Ident, message: ServerMessage) -> proc_macr
     );
 
     let (request_cases, response_cases) = build_declarations(&message.handlers);
+    let response_names: Vec<Ident> = response_cases.iter().map(|c| c.name.clone()).collect();
+    let streams = message.handlers.iter().any(|h| h.response.streams());
+    let request_names: Vec<Ident> = message
+        .handlers
+        .iter()
+        .map(|h| h.request_case.ident.clone())
+        .collect();
+    let request_name_strs: Vec<String> = request_names.iter().map(|n| n.to_string()).collect();
+    let capability = message.interface_name.to_string();
 
     let handlers = message.handlers;
+    let notifications = message.notifications;
+    let notification_names: Vec<Ident> = notifications.iter().map(|n| n.name.clone()).collect();
+
+    // Streamed answers stand in for their frames until a session sends them, so never go out
+    let (stream_case, stream_response) = if streams {
+        (
+            quote! {
+                ,
+                #[serde(skip)]
+                Streamed(::cliff::server::ResponseStream<#response_type_name>)
+            },
+            quote! {
+                fn into_stream(self) -> ::core::result::Result<::cliff::server::ResponseStream<Self>, Self> {
+                    match self {
+                        #response_type_name::Streamed(frames) => Ok(frames),
+                        response => Err(response),
+                    }
+                }
+            },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
 
     quote! {
         #[derive(::cliff::serde::Serialize, ::cliff::serde::Deserialize, Debug)]
@@ -60,23 +92,74 @@ fn build_server_message(router_type: Ident, message: ServerMessage) -> proc_macr
             }
         }
 
+        impl ::cliff::codec::MethodCall for #request_type_name {
+            fn method(&self) -> &'static str {
+                match self {
+                    #(#request_type_name::#request_names { .. } => #request_name_strs,)*
+                }
+            }
+
+            fn id(&self) -> ::cliff::codec::Id {
+                match self {
+                    #(#request_type_name::#request_names { rqs_id, .. } => rqs_id.clone(),)*
+                }
+            }
+
+            fn capability(&self) -> &'static str {
+                #capability
+            }
+
+            fn trace(&self) -> Option<::cliff::codec::TraceContext> {
+                match self {
+                    #(#request_type_name::#request_names { trace, .. } => *trace,)*
+                }
+            }
+        }
+
         impl ::cliff::actix::Message for #request_type_name {
             type Result = #response_type_name;
         }
 
-        #[derive(::cliff::serde::Serialize, ::cliff::serde::Deserialize, ::cliff::actix::Message, Debug)]
+        #[derive(::cliff::serde::Serialize, ::cliff::serde::Deserialize, ::cliff::actix::Message, Clone, Debug)]
         #[rtype(result = "()")]
         #[serde(crate = "::cliff::serde")]
         pub enum #response_type_name {
-            #response_cases
+            #(#response_cases),*
+            #(, #notifications)*
+            #stream_case
         }
 
         impl ::cliff::codec::RpcMessage for #response_type_name {
             fn rpc_message_type(&self) -> ::cliff::codec::RpcMessageType {
-                ::cliff::codec::RpcMessageType::Response
+                #[allow(unreachable_patterns)]
+                match self {
+                    #response_type_name::Error { .. } => ::cliff::codec::RpcMessageType::Error,
+                    #(#response_type_name::#notification_names { .. } => ::cliff::codec::RpcMessageType::Notification,)*
+                    _ => ::cliff::codec::RpcMessageType::Response,
+                }
+            }
+        }
+
+        impl ::cliff::codec::Reply for #response_type_name {
+            fn id(&self) -> Option<::cliff::codec::Id> {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#response_type_name::#response_names { rqs_id, .. } => Some(rqs_id.clone()),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl ::cliff::codec::ErrorResponse for #response_type_name {
+            fn error_response(rqs_id: ::cliff::codec::Id, error: ::cliff::codec::RpcError) -> Self {
+                #response_type_name::Error { rqs_id, error }
             }
         }
 
+        impl ::cliff::server::StreamResponse for #response_type_name {
+            #stream_response
+        }
+
         impl<A, M> ::cliff::actix::dev::MessageResponse<A, M> for #response_type_name
         where
             A: ::cliff::actix::Actor,
@@ -89,16 +172,28 @@ fn build_server_message(router_type: Ident, message: ServerMessage) -> proc_macr
             }
         }
 
-        impl Handler<#request_type_name> for #router_type {
+        impl Handler<::cliff::context::Request<#request_type_name>> for #router_type {
             type Result = #response_type_name;
 
-            fn handle(&mut self, msg: #request_type_name, _ctx: &mut Self::Context) -> Self::Result {
+            fn handle(&mut self, request: ::cliff::context::Request<#request_type_name>, ctx: &mut Self::Context) -> Self::Result {
                 use #request_type_name::*;
                 use #response_type_name::*;
 
-                match msg {
+                // Handler blocks can look at who's asking and when they stop waiting
+                let ::cliff::context::Request { message: msg, context } = request;
+
+                // What they log is tagged with the request's trace, which clients they call on
+                // pass along
+                let span = context.span.clone();
+                let _span = span.enter();
+                let _trace = ::cliff::trace::enter(context.trace);
+
+                // A panicking block answers with an error and restarts the router, see
+                // `cliff::server::isolate`
+                let isolated = context.clone();
+                ::cliff::server::isolate(ctx, &isolated, move || match msg {
                     #(#handlers)*
-                }
+                })
             }
         }
 
@@ -108,24 +203,39 @@ fn build_server_message(router_type: Ident, message: ServerMessage) -> proc_macr
 
 fn build_declarations(
     handlers: &[RequestHandler],
-) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+) -> (proc_macro2::TokenStream, Vec<CaseDeclaration>) {
     let request_cases: Vec<CaseDeclaration> = handlers
         .iter()
         .map(|h| CaseDeclaration::from(&h.request_case))
         .collect();
 
+    // Every router answers failures through the same `Error` case, so it's always declared here
+    let error_case = CaseDeclaration {
+        name: Ident::new("Error", proc_macro2::Span::call_site()),
+        fields: vec![CaseField {
+            name: Ident::new("error", proc_macro2::Span::call_site()),
+            ty: parse_quote! { ::cliff::codec::RpcError },
+        }],
+        traced: false,
+    };
+
+    // And streams all end with the same `End` case
+    let end_case = CaseDeclaration {
+        name: Ident::new("End", proc_macro2::Span::call_site()),
+        fields: vec![],
+        traced: false,
+    };
+    let streams = handlers.iter().any(|h| h.response.streams());
+
     let response_cases: Vec<CaseDeclaration> = handlers
         .iter()
-        .flat_map(|h| match h.response.clone() {
-            Response::Base { case } => vec![case],
-            Response::Conditional { cases } => cases.iter().map(|c| c.response.clone()).collect(),
-        })
+        .flat_map(|h| h.response.cases())
         .filter_map(|c| CaseDeclaration::try_from(&c).ok())
+        .filter(|c| c.name != error_case.name)
+        .chain(std::iter::once(error_case.clone()))
+        .chain(if streams { Some(end_case) } else { None })
         .unique_by(|c| c.name.clone())
         .collect();
 
-    (
-        quote! { #(#request_cases),* },
-        quote! { #(#response_cases),* },
-    )
+    (quote! { #(#request_cases),* }, response_cases)
 }
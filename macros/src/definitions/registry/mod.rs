@@ -4,10 +4,12 @@ use heck::SnakeCase;
 
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
-use syn::{bracketed, Ident, Result, Token};
+use syn::{bracketed, Expr, Ident, Result, Token};
 
 mod provide_keywords {
     syn::custom_keyword!(from);
+    syn::custom_keyword!(with);
+    syn::custom_keyword!(authorized);
 }
 
 #[derive(Clone, Debug)]
@@ -22,6 +24,11 @@ pub struct Capability {
     pub(crate) provider: Ident,
 
     pub(crate) name: Ident,
+
+    pub(crate) interceptors: Vec<Expr>,
+
+    /// Who may call which methods, `cliff::auth::Policy::default()` unless given
+    pub(crate) policy: Option<Expr>,
 }
 
 #[derive(Clone, Debug)]
@@ -36,11 +43,34 @@ impl Parse for Provide {
 
         let content;
         let _ = bracketed!(content in input);
-        let capabilities = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?
+        let names = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?;
+
+        // `with [Interceptor, ...]` wraps every capability's requests in the listed interceptors
+        let mut interceptors = vec![];
+        if input.peek(provide_keywords::with) {
+            let _: provide_keywords::with = input.parse()?;
+
+            let content;
+            let _ = bracketed!(content in input);
+            interceptors = Punctuated::<Expr, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect();
+        }
+
+        // `authorized <Policy>` limits who may call every capability's methods
+        let mut policy = None;
+        if input.peek(provide_keywords::authorized) {
+            let _: provide_keywords::authorized = input.parse()?;
+            policy = Some(input.parse()?);
+        }
+
+        let capabilities = names
             .iter()
             .map(|name| Capability {
                 provider: provider.clone(),
                 name: name.clone(),
+                interceptors: interceptors.clone(),
+                policy: policy.clone(),
             })
             .collect();
 
@@ -77,7 +107,7 @@ impl ToTokens for Provide {
         let deregister_capabilities: Vec<_> = capabilities
             .iter()
             .map(|capability| {
-                let Capability { provider: _, name } = capability;
+                let Capability { name, .. } = capability;
 
                 let capability_name = Ident::new(
                     name.to_string().as_str().to_snake_case().as_str(),
@@ -97,16 +127,47 @@ impl ToTokens for Provide {
             })
             .collect();
 
+        let replay_arms: Vec<_> = capabilities
+            .iter()
+            .map(|capability| capability.to_replay_tokens())
+            .collect();
+        let stdio_arms: Vec<_> = capabilities
+            .iter()
+            .map(|capability| capability.to_stdio_tokens())
+            .collect();
+        let unknown_capability = format!("{} provides no {{}} capability", provider);
+        let metrics_capability = format!("{}_metrics", var_name);
+
         let stream = quote! {
-            async fn register_providers() -> ::core::result::Result<::actix::Addr<#provider>, ::failure::Error> {
+            async fn register_providers() -> ::core::result::Result<Vec<::registry::cliff::server::ServerHandle>, ::failure::Error> {
                 use ::registry::actix::*;
 
-                let #var_name = #provider::start_default();
+                let #var_name = Supervisor::start(|_| #provider::default());
                 let registry_client = ::registry::ProviderClient::connect_default().await?;
+                let mut servers = vec![];
 
                 #(#capabilities)*
 
-                Ok(#var_name)
+                // Every provider answers for its own metrics, only ever on the local machine
+                servers.push({
+                    let address = ::registry::cliff::transport::Address::Unix(
+                        ::registry::cliff::transport::runtime_dir()
+                            .join(format!("{}.{}", #metrics_capability, ::registry::uuid::Uuid::new_v4()))
+                    ).to_string();
+                    let server = ::registry::cliff::server::IpcServer::<::registry::MetricsRequest, ::registry::Metrics>::serve(
+                        address.as_str(),
+                        Supervisor::start(|_| ::registry::Metrics::default()),
+                    )?;
+                    registry_client.send(::registry::Register {
+                        capability: #metrics_capability.to_string(),
+                        address: server.address().to_string(),
+                    })
+                    .await?;
+
+                    server
+                });
+
+                Ok(servers)
             }
 
             async fn deregister_providers() {
@@ -117,6 +178,43 @@ impl ToTokens for Provide {
                     .expect("Couldn't connect with registry to deregister capabilities");
 
                 #(#deregister_capabilities)*
+
+                registry_client.send(::registry::Deregister {
+                    capability: #metrics_capability.to_string(),
+                })
+                .await
+                .expect(concat!("Couldn't deregister ", #metrics_capability));
+            }
+
+            async fn serve_stdio(capability: &str) -> ::core::result::Result<(), ::failure::Error> {
+                use ::registry::actix::*;
+
+                let #var_name = Supervisor::start(|_| #provider::default());
+
+                match capability {
+                    #(#stdio_arms)*
+                    _ => Err(::failure::format_err!(#unknown_capability, capability)),
+                }
+            }
+
+            async fn replay_capture(capability: &str, path: &str) -> ::core::result::Result<(), ::failure::Error> {
+                use ::registry::actix::*;
+
+                let #var_name = Supervisor::start(|_| #provider::default());
+
+                let mismatches = match capability {
+                    #(#replay_arms)*
+                    _ => return Err(::failure::format_err!(#unknown_capability, capability)),
+                };
+
+                for mismatch in mismatches.iter() {
+                    println!("{}", mismatch);
+                }
+
+                match mismatches.len() {
+                    0 => Ok(()),
+                    n => Err(::failure::format_err!("{} responses differ from the capture", n)),
+                }
             }
         };
 
@@ -126,12 +224,18 @@ impl ToTokens for Provide {
 
 impl ToTokens for Capability {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let Capability { provider, name } = self;
+        let Capability {
+            provider,
+            name,
+            interceptors,
+            ..
+        } = self;
 
         let var_name = Ident::new(
             provider.to_string().as_str().to_snake_case().as_str(),
             provider.span(),
         );
+        let config = self.to_config_tokens();
 
         let capability_name = Ident::new(
             name.to_string().as_str().to_snake_case().as_str(),
@@ -142,28 +246,97 @@ impl ToTokens for Capability {
         let request_type = Ident::new(format!("{}Request", name).as_str(), name.span());
 
         let stream = quote! {
-            ::registry::actix::Arbiter::spawn(Box::pin({
-                let #var_name = #var_name.clone();
-                let registry_client = registry_client.clone();
-
-                async move {
-                    let path = format!("/tmp/central.{}.{}", #capability_name_str, ::registry::uuid::Uuid::new_v4());
-                    ::registry::cliff::server::IpcServer::<#request_type, #provider>::serve(path.as_str(), #var_name)
-                        .expect("Couldn't start server for capability: #capability");
-                    registry_client.send(::registry::Register {
-                        capability: #capability_name_str.to_string(),
-                        address: path,
-                    })
-                    .await
-                    .expect(format!("Error sending Regiter message for {}", #capability_name_str).as_str());
-                }
-            }));
+            servers.push({
+                // With `CAPABILITY_HOST` set, capabilities are reachable over tcp on any free port,
+                // by whoever their policy lets in without credentials. Those without an
+                // `authorized` policy allowing `Anyone` refuse to start there
+                let address = match std::env::var("CAPABILITY_HOST") {
+                    Ok(host) => format!("tcp://{}:0", host),
+                    Err(_) => ::registry::cliff::transport::Address::Unix(
+                        ::registry::cliff::transport::runtime_dir()
+                            .join(format!("{}.{}", #capability_name_str, ::registry::uuid::Uuid::new_v4()))
+                    ).to_string(),
+                };
+                let server = ::registry::cliff::server::IpcServer::<#request_type, #provider>::serve_intercepted(
+                    address.as_str(),
+                    #var_name.clone(),
+                    #config,
+                    ::registry::cliff::intercept::Interceptors::new()#(.with(#interceptors))*,
+                )
+                .unwrap_or_else(|e| {
+                    panic!("Couldn't start server for capability {}: {}", #capability_name_str, e)
+                });
+                registry_client.send(::registry::Register {
+                    capability: #capability_name_str.to_string(),
+                    address: server.address().to_string(),
+                })
+                .await
+                .expect(format!("Error sending Regiter message for {}", #capability_name_str).as_str());
+
+                server
+            });
         };
 
         tokens.append_all(stream)
     }
 }
 
+impl Capability {
+    fn to_config_tokens(&self) -> proc_macro2::TokenStream {
+        match &self.policy {
+            Some(policy) => quote! {
+                ::registry::cliff::server::ServerConfig::default().authorized(#policy)
+            },
+            None => quote! { ::registry::cliff::server::ServerConfig::default() },
+        }
+    }
+
+    fn to_stdio_tokens(&self) -> proc_macro2::TokenStream {
+        let Capability {
+            provider,
+            name,
+            interceptors,
+            ..
+        } = self;
+
+        let var_name = Ident::new(
+            provider.to_string().as_str().to_snake_case().as_str(),
+            provider.span(),
+        );
+        let config = self.to_config_tokens();
+        let capability_name_str = name.to_string().as_str().to_snake_case();
+        let request_type = Ident::new(format!("{}Request", name).as_str(), name.span());
+
+        quote! {
+            #capability_name_str => {
+                ::registry::cliff::server::IpcServer::<#request_type, #provider>::serve_stdio_intercepted(
+                    #var_name,
+                    #config,
+                    ::registry::cliff::intercept::Interceptors::new()#(.with(#interceptors))*,
+                )
+                .await
+            }
+        }
+    }
+
+    fn to_replay_tokens(&self) -> proc_macro2::TokenStream {
+        let Capability { provider, name, .. } = self;
+
+        let var_name = Ident::new(
+            provider.to_string().as_str().to_snake_case().as_str(),
+            provider.span(),
+        );
+        let capability_name_str = name.to_string().as_str().to_snake_case();
+        let request_type = Ident::new(format!("{}Request", name).as_str(), name.span());
+
+        quote! {
+            #capability_name_str => {
+                ::registry::cliff::capture::replay::<#request_type, #provider>(path, #var_name).await?
+            }
+        }
+    }
+}
+
 impl ToTokens for Interface {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         let Interface { capabilities } = self;
@@ -198,11 +371,11 @@ impl ToTokens for Interface {
 
             async fn require<T: ::registry::cliff::client::IpcClient + RegistryRequireableCapability>() -> ::core::result::Result<::registry::actix::Addr<T>, ::registry::failure::Error> {
                 let interface_client = ::registry::InterfaceClient::connect_default().await?;
-                let path = interface_client
+                let address = interface_client
                     .send(::registry::Require { capability: T::get_capability_name() })
                     .await??;
 
-                T::connect(path.as_str()).await
+                T::connect(address.as_str()).await
             }
         };
 
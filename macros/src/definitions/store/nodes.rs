@@ -28,6 +28,9 @@ pub struct QueryAction {
 
     pub(crate) run_block: Block,
 
+    /// Answered with a `response_name` frame for each `item` the run block returns. The block
+    /// loads every row before the first frame goes out, so the result has to fit in memory
+    pub(crate) streamed: bool,
     pub(crate) response_name: Ident,
     pub(crate) response_fields: Vec<QueryResponseField>,
 
@@ -8,6 +8,7 @@ mod store_keywords {
     syn::custom_keyword!(command);
     syn::custom_keyword!(query);
     syn::custom_keyword!(into);
+    syn::custom_keyword!(stream);
 }
 
 impl Parse for Ipc {
@@ -135,6 +136,12 @@ impl Parse for QueryAction {
 
         let run_block = input.parse()?;
         let _: store_keywords::into = input.parse()?;
+        let streamed = if input.peek(store_keywords::stream) {
+            let _: store_keywords::stream = input.parse()?;
+            true
+        } else {
+            false
+        };
         let response_name = input.parse()?;
 
         let content;
@@ -157,6 +164,7 @@ impl Parse for QueryAction {
 
             run_block,
 
+            streamed,
             response_name,
             response_fields,
 
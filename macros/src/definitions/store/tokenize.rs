@@ -28,14 +28,30 @@ impl ToTokens for Ipc {
             .map(|act| (act.to_router_tokens(), act.to_client_tokens()))
             .unzip();
 
-        let qry_response_mappings: Vec<_> = get_response_mappings(query_actions);
-        let query_response_types: Vec<_> = query_actions
+        let (streamed_actions, waited_actions): (Vec<QueryAction>, Vec<QueryAction>) =
+            query_actions.iter().cloned().partition(|act| act.streamed);
+        let qry_response_mappings: Vec<_> = get_response_mappings(&waited_actions);
+        let qry_stream_mappings: Vec<_> = streamed_actions
+            .iter()
+            .map(|act| {
+                let QueryAction {
+                    result_type,
+                    result_block,
+                    ..
+                } = act;
+                let response = act.to_result_mapping();
+
+                quote! { #response => [ #result_type: #result_block ] }
+            })
+            .collect();
+        let query_response_types: Vec<_> = waited_actions
             .iter()
             .map(|act| act.result_type.clone())
             .unique()
             .collect();
 
         let stream = quote! {
+            #[allow(unused_imports)]
             use failure::{format_err, Error};
 
             use ::actix::prelude::*;
@@ -49,14 +65,23 @@ impl ToTokens for Ipc {
 
             impl Default for #store_name {
                 fn default() -> Self {
-                    ::dotenv::dotenv().ok();
+                    let connection = Self::connect().unwrap_or_else(|e| panic!("{}", e));
+
+                    #store_name { connection }
+                }
+            }
 
-                    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL env var not found");
+            impl #store_name {
+                /// Opens a connection to the store's database, which streamed queries take one of
+                /// for themselves
+                fn connect() -> Result<PgConnection, Error> {
+                    ::dotenv::dotenv().ok();
 
-                    let connection = PgConnection::establish(&database_url)
-                        .unwrap_or_else(|_| panic!("Couldn't connect to {}", database_url));
+                    let database_url = std::env::var("DATABASE_URL")
+                        .map_err(|_| format_err!("DATABASE_URL env var not found"))?;
 
-                    #store_name { connection }
+                    PgConnection::establish(&database_url)
+                        .map_err(|_| format_err!("Couldn't connect to {}", database_url))
                 }
             }
 
@@ -64,6 +89,27 @@ impl ToTokens for Ipc {
                 type Context = Context<Self>;
             }
 
+            // Panics can come from a broken connection, so restarts open a new one
+            impl Supervised for #store_name {
+                fn restarting(&mut self, _ctx: &mut Self::Context) {
+                    *self = Self::default();
+                }
+            }
+
+            fn store_error(error: &Error) -> ::cliff::codec::RpcError {
+                use ::cliff::codec::{ErrorCode, RpcError};
+
+                let code = if let Some(::diesel::result::Error::NotFound) = error.downcast_ref() {
+                    ErrorCode::NotFound
+                } else if error.downcast_ref::<::uuid::Error>().is_some() {
+                    ErrorCode::InvalidUuid
+                } else {
+                    ErrorCode::StoreFailure
+                };
+
+                RpcError::from_failure(code, error)
+            }
+
             ::cliff::router! {
                 #store_name;
                 [
@@ -85,8 +131,8 @@ impl ToTokens for Ipc {
                         #(#cmd_client_handlers),*
                     ],
                     response_mapping => [
-                        Success => [ () ],
-                        Error => [ () ]
+                        Success => [ Result<(), Error>: Ok(()) ],
+                        Error { error } => [ Result<(), Error>: Err(error.into()) ]
                     ]
                 }
             }
@@ -98,9 +144,12 @@ impl ToTokens for Ipc {
                     ],
                     response_mapping => [
                         #(#qry_response_mappings,)*
-                        Error { description  } => [
-                            #(Result<#query_response_types, Error>: Err(format_err!("{}",description))),*
+                        Error { error } => [
+                            #(Result<#query_response_types, Error>: Err(error.clone().into())),*
                         ]
+                    ],
+                    stream_mapping => [
+                        #(#qry_stream_mappings),*
                     ]
                 }
             }
@@ -129,7 +178,7 @@ impl CommandAction {
             #action_name #fields -> {
                 let res = #block;
             } => [
-                let Err(e) = res => Error,
+                let Err(e) = res => Error { error: store_error(&e) },
                 => Success
             ]
         }
@@ -142,7 +191,7 @@ impl CommandAction {
             ..
         } = self;
 
-        quote! { #action_name #fields wait }
+        quote! { #action_name #fields wait Result<(), Error> }
     }
 }
 
@@ -152,6 +201,7 @@ impl QueryAction {
             action_name,
             action_fields,
             run_block,
+            streamed,
             response_name,
             response_fields,
             ..
@@ -184,12 +234,22 @@ impl QueryAction {
             Some(quote! { {#(#fields),*} })
         };
 
+        // Streamed queries send a frame for each `item` of the stream the run block returns, so
+        // it can page through rows while they're being sent
+        let response = if *streamed {
+            quote! {
+                for item in result.unwrap() => #response_name #type_decls #field_values
+            }
+        } else {
+            quote! { #response_name #type_decls #field_values }
+        };
+
         quote! {
             #action_name #action_fields -> {
                 let result = #run_block;
             } => [
-                let Err(e) = result => Error [String] { description: format!("{}", e) },
-                => #response_name #type_decls #field_values
+                let Err(e) = result => Error { error: store_error(&e) },
+                => #response
             ]
         }
     }
@@ -198,11 +258,16 @@ impl QueryAction {
         let QueryAction {
             action_name,
             action_fields,
+            streamed,
             result_type,
             ..
         } = self;
 
-        quote! { #action_name #action_fields wait Result<#result_type, Error> }
+        if *streamed {
+            quote! { #action_name #action_fields stream #result_type }
+        } else {
+            quote! { #action_name #action_fields wait Result<#result_type, Error> }
+        }
     }
 
     fn to_result_mapping(&self) -> ResultMapping {
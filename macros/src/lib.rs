@@ -68,20 +68,45 @@ pub fn run_provide(tokens: TokenStream) -> TokenStream {
         fn main() -> Result<(), ::registry::failure::Error> {
             ::registry::actix_rt::System::new("main").block_on(async move {
                 dotenv::dotenv().ok();
+
+                // `replay <capability> <capture>` answers a captured connection instead of serving
+                let args: Vec<String> = std::env::args().skip(1).collect();
+                if let Some("replay") = args.first().map(String::as_str) {
+                    let result = match (args.get(1), args.get(2)) {
+                        (Some(capability), Some(path)) => replay_capture(capability, path).await,
+                        _ => Err(::registry::failure::format_err!("Usage: replay <capability> <capture>")),
+                    };
+                    ::registry::actix::System::current().stop();
+
+                    return result;
+                }
+
                 let log_dir = std::env::var("LOG_DIRECTORY")?;
 
                 let file_appender = ::registry::tracing_appender::rolling::daily(&log_dir, #file_name);
                 let (non_blocking, _guard) = ::registry::tracing_appender::non_blocking(file_appender);
                 ::registry::tracing_subscriber::fmt().with_writer(non_blocking).init();
 
-                let _: Result<(), ::registry::failure::Error> = {
-                    register_providers().await?;
+                // `stdio <capability>` serves a single connection for whoever spawned us, e.g. with
+                // a `spawn://<this binary> stdio <capability>` address, and skips the registry
+                if let Some("stdio") = args.first().map(String::as_str) {
+                    let result = match args.get(1) {
+                        Some(capability) => serve_stdio(capability).await,
+                        None => Err(::registry::failure::format_err!("Usage: stdio <capability>")),
+                    };
+                    ::registry::actix::System::current().stop();
+
+                    return result;
+                }
 
-                    ::registry::tokio::signal::ctrl_c().await?;
-                    Ok(())
-                };
+                let servers = register_providers().await?;
+                ::registry::shutdown_signal().await?;
 
+                // Nobody gets pointed our way while the servers drain
                 deregister_providers().await;
+                ::registry::futures::future::join_all(
+                    servers.iter().map(|server| server.shutdown(::registry::cliff::server::SHUTDOWN_DEADLINE))
+                ).await;
                 ::registry::actix::System::current().stop();
 
                 Ok(())
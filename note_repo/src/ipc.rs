@@ -1,5 +1,6 @@
 use std::env::{current_dir, set_current_dir, var};
 
+use cliff::rpc::{ErrorCode, RpcError};
 use cliff::{client, router};
 
 use actix::prelude::*;
@@ -11,30 +12,32 @@ use failure::{format_err, Error};
 use crate::runners::*;
 use models::{NoteDescriptor, NoteRef};
 
+#[derive(Default)]
 pub struct NoteRepo;
 
 impl Actor for NoteRepo {
     type Context = Context<Self>;
-}
 
-impl Default for NoteRepo {
-    fn default() -> Self {
+    // Runs again after every restart, which is all a restart has to redo since notes live on
+    // disk
+    fn started(&mut self, _ctx: &mut Self::Context) {
         dotenv::dotenv().ok();
 
         let current = current_dir();
         let home = var("NOTE_HOME").map(|s| s.into()).or(current).unwrap();
 
         set_current_dir(home).ok();
-
-        NoteRepo
     }
 }
 
+impl Supervised for NoteRepo {}
+
 router! {
     NoteRepo;
     [
         NoteCommand [
             Create { reference: NoteRef, body: String } -> {
+                cliff::server::notify(NoteChanged { reference: reference.clone() });
                 if let NoteRef::Path(path) = reference {
                     info!("Creating note at {} with initial body {}", path, body);
                     // TODO
@@ -42,17 +45,20 @@ router! {
                 }
             } => Success,
             Update { reference: NoteRef, body: String } -> {
+                cliff::server::notify(NoteChanged { reference: reference.clone() });
                 if let NoteRef::Path(path) = reference  {
                     info!("Updating note at {} with body {}", path, body);
                     // TODO
                 }
             } => Success,
             Delete { reference: NoteRef } -> {
+                cliff::server::notify(NoteChanged { reference: reference.clone() });
                 if let NoteRef::Path(path) = reference {
                     info!("Deleting note at {}", path);
                     // TODO
                 }
             } => Success,
+            notify NoteChanged { reference: NoteRef },
         ],
         NoteQuery [
             GetContent { reference: NoteRef } -> {
@@ -62,15 +68,14 @@ router! {
                     Err(format_err!("Invalid Note reference"))
                 };
             } => [
-                let Err(e) = content => Error [String] { description: format!("{}", e) },
+                let Err(e) = content => Error { error: RpcError::from_failure(ErrorCode::StoreFailure, &e) },
                 => Content [String] { content: content.unwrap() },
             ],
             GetIndex -> {
-                let index = get_all_descriptors();
-                info!("Descriptors: {:?}", index);
+                let index = stream_descriptors();
             } => [
-                let Err(e) = index => Error [String] { description: format!("{}", e) },
-                => Index [Vec<NoteDescriptor>] { index: index.unwrap() }
+                let Err(e) = index => Error { error: RpcError::from_failure(ErrorCode::StoreFailure, &e) },
+                => for descriptor in index.unwrap() => Descriptor [NoteDescriptor] { descriptor }
             ],
         ],
         NoteRepoStatus [
@@ -86,25 +91,27 @@ client! {
             Update { reference: NoteRef, body: String },
             Delete { reference: NoteRef },
         ],
+        notifications => [NoteChanged],
     }
 }
 
 client! {
     NoteQuery {
         actions => [
-            GetIndex wait Result<Vec<NoteDescriptor>, Error>,
+            GetIndex stream NoteDescriptor,
             GetContent { reference: NoteRef } wait Result<String, Error>,
         ],
         response_mapping => [
-            Index { index } => [
-                Result<Vec<NoteDescriptor>, Error>: Ok(index)
-            ],
             Content { content } => [
                 Result<String, Error>: Ok(content)
             ],
-            Error { description } => [
-                Result<Vec<NoteDescriptor>, Error>: Err(format_err!("{}", description)),
-                Result<String, Error>: Err(format_err!("{}", description))
+            Error { error } => [
+                Result<String, Error>: Err(error.into())
+            ]
+        ],
+        stream_mapping => [
+            Descriptor { descriptor } => [
+                NoteDescriptor: descriptor
             ]
         ]
     }
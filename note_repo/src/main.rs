@@ -1,8 +1,11 @@
 mod ipc;
 mod runners;
 
+use cliff::auth::{Allow, Policy};
+
 use ipc::{NoteCommandRequest, NoteQueryRequest, NoteRepo, NoteRepoStatusRequest};
 
 registry::run_provide! {
     NoteRepo => [NoteCommand, NoteQuery, NoteRepoStatus]
+        authorized Policy::default().method("Delete", Allow::Owner)
 }
@@ -3,7 +3,13 @@ use std::path::Path;
 
 use std::fs::{read_to_string, File};
 use std::io::{BufRead, BufReader};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use cliff::futures::channel::mpsc::{channel, Receiver};
+use cliff::futures::executor::block_on;
+use cliff::futures::SinkExt;
+use cliff::rpc::RpcError;
 
 use tracing::info;
 
@@ -11,30 +17,43 @@ use models::{NoteDescriptor, NoteRef};
 
 use failure::Error;
 
+/// How many descriptors are read ahead of the ones sent
+const DESCRIPTOR_BUFFER: usize = 64;
+
 pub fn get_note(path: String) -> Result<String, Error> {
     Ok(read_to_string(path)?)
 }
 
-pub fn get_all_descriptors() -> Result<Vec<NoteDescriptor>, Error> {
+/// Every note's descriptor, sent as soon as its heading is read and there's room for it
+pub fn stream_descriptors() -> Result<Receiver<Result<NoteDescriptor, RpcError>>, Error> {
     info!("Working from: {:?}", current_dir());
-    let ls = Command::new("fd")
+    let mut ls = Command::new("fd")
         .args(&["-e", "md", "-c", "never"])
-        .output()?;
+        .stdout(Stdio::piped())
+        .spawn()?;
 
-    Ok(ls
-        .stdout
-        .lines()
-        .filter_map(|res| res.ok())
-        .map(|path| {
+    let (mut tx, rx) = channel(DESCRIPTOR_BUFFER);
+    thread::spawn(move || {
+        let out = ls.stdout.take().map(BufReader::new);
+        let paths = out.into_iter().flat_map(|out| out.lines());
+
+        for path in paths.filter_map(|res| res.ok()) {
             let default = "".to_string();
             let title = read_heading(path.clone()).unwrap_or(default);
 
-            NoteDescriptor {
+            let descriptor = NoteDescriptor {
                 title,
                 reference: NoteRef::Path(path),
+            };
+            // Nobody's listening anymore
+            if block_on(tx.send(Ok(descriptor))).is_err() {
+                break;
             }
-        })
-        .collect())
+        }
+        let _ = ls.wait();
+    });
+
+    Ok(rx)
 }
 
 fn read_heading<P>(path: P) -> Result<String, Error>
@@ -31,10 +31,10 @@ macros::ipc! {
                 content
             } as String,
             GetIndex -> {
-                get_all_descriptors(&self.connection)
-            } into Index { index: Vec<NoteDescriptor> = result.unwrap() } => {
-                index
-            } as Vec<NoteDescriptor>
+                Self::connect().map(stream_descriptors)
+            } into stream Descriptor { descriptor: NoteDescriptor = item } => {
+                descriptor
+            } as NoteDescriptor
         ]
     }
 }
@@ -1,3 +1,10 @@
+use std::thread;
+
+use cliff::futures::channel::mpsc::{channel, Receiver};
+use cliff::futures::executor::block_on;
+use cliff::futures::SinkExt;
+use cliff::rpc::{ErrorCode, RpcError};
+
 use failure::Error;
 
 use diesel::prelude::*;
@@ -7,6 +14,9 @@ use uuid::Uuid;
 use models::notes;
 pub use models::*;
 
+/// How many notes are fetched at a time while paging through the index
+const PAGE_SIZE: i64 = 64;
+
 #[derive(Insertable)]
 #[table_name = "notes"]
 struct NewNote<'a> {
@@ -52,10 +62,47 @@ pub fn get_note(conn: &PgConnection, note_id: &str) -> Result<Note, Error> {
     Ok(note)
 }
 
-pub fn get_all_descriptors(conn: &PgConnection) -> Result<Vec<NoteDescriptor>, Error> {
+/// Every note's descriptor, ordered by id and fetched a page at a time over `conn` as there's
+/// room for them
+pub fn stream_descriptors(conn: PgConnection) -> Receiver<Result<NoteDescriptor, RpcError>> {
+    let (mut tx, rx) = channel(PAGE_SIZE as usize);
+    thread::spawn(move || {
+        let mut after = None;
+        loop {
+            let page = match get_descriptor_page(&conn, after) {
+                Ok(page) => page,
+                Err(e) => {
+                    let error = RpcError::from_failure(ErrorCode::StoreFailure, &e);
+                    let _ = block_on(tx.send(Err(error)));
+                    return;
+                }
+            };
+            let last = page.len() < PAGE_SIZE as usize;
+            after = page.last().map(|note| note.id);
+
+            for note in page {
+                // Nobody's listening anymore
+                if block_on(tx.send(Ok(note.into()))).is_err() {
+                    return;
+                }
+            }
+            if last {
+                return;
+            }
+        }
+    });
+
+    rx
+}
+
+/// The page of notes following the one with id `after`, or the first one
+fn get_descriptor_page(conn: &PgConnection, after: Option<Uuid>) -> Result<Vec<Note>, Error> {
     use self::notes::dsl::*;
 
-    let results = notes.load::<Note>(conn)?;
+    let mut query = notes.order(id).limit(PAGE_SIZE).into_boxed();
+    if let Some(after) = after {
+        query = query.filter(id.gt(after));
+    }
 
-    Ok(results.iter().cloned().map(|n| n.into()).collect())
+    Ok(query.load::<Note>(conn)?)
 }
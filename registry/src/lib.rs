@@ -2,6 +2,7 @@ pub extern crate actix;
 pub extern crate actix_rt;
 pub extern crate cliff;
 pub extern crate failure;
+pub extern crate futures;
 pub extern crate tokio;
 pub extern crate tracing;
 pub extern crate tracing_appender;
@@ -10,18 +11,50 @@ pub extern crate uuid;
 
 use actix::prelude::*;
 
-use failure::{format_err, Error, ResultExt};
+use failure::{Error, ResultExt};
+
+use tokio::signal::unix::{signal, SignalKind};
 
 use tracing::info;
 
 use im::HashMap;
 
+use cliff::auth::{Allow, Policy};
 use cliff::client::IpcClient;
-use cliff::server::IpcServer;
+use cliff::rpc::{ErrorCode, RpcError};
+use cliff::server::{IpcServer, ServerConfig, ServerHandle};
+use cliff::transport::{runtime_dir, Address};
 use cliff::{client, router};
 
 pub use macros::*;
 
+/// Where the registry listens unless `REGISTRY_ADDRESS` says otherwise, in the user's runtime
+/// directory
+pub fn default_address() -> String {
+    Address::Unix(runtime_dir().join("registry")).to_string()
+}
+
+/// The registry's address, which providers and interfaces on other machines or in containers
+/// set through `REGISTRY_ADDRESS`
+pub fn registry_address() -> String {
+    std::env::var("REGISTRY_ADDRESS").unwrap_or_else(|_| default_address())
+}
+
+/// Resolves once the process is asked to stop, with either ctrl-c or SIGTERM
+pub async fn shutdown_signal() -> Result<(), Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Ctrl-C received, shutting down");
+        }
+        _ = terminate.recv() => info!("SIGTERM received, shutting down"),
+    }
+
+    Ok(())
+}
+
 pub struct Registry {
     providers: HashMap<String, String>,
 }
@@ -30,6 +63,10 @@ impl Actor for Registry {
     type Context = Context<Self>;
 }
 
+// Registrations are kept across restarts, since providers only register when they start. Every
+// handler changes them with a single insert or remove, so a panic can't leave them half done
+impl Supervised for Registry {}
+
 impl Default for Registry {
     fn default() -> Self {
         Registry {
@@ -42,19 +79,19 @@ router! {
     Registry [
         // Interface
         Require { capability: String } -> {
-            info!("Client Requiring Capability: {}", capability);
+            info!("Client Requiring Capability: {} (connection {})", capability, context.connection);
             let addr = self.providers.get(&capability);
         } => [
             let Some(addr) = addr => Capability [String] { address: addr.clone() },
-            => Error [String] { description: "Capability Not Found".into() }
+            => Error { error: RpcError::new(ErrorCode::NotFound, "Capability Not Found") }
         ],
         // Provider
         Register { capability: String, address: String } -> {
-            info!("Client Registering Capability: {}", capability);
+            info!("Client Registering Capability: {} (connection {}, {:?})", capability, context.connection, context.peer);
             self.providers.insert(capability, address);
         } => Success,
         Deregister { capability: String } -> {
-            info!("Client Deregistering Capability: {}", capability);
+            info!("Client Deregistering Capability: {} (connection {}, {:?})", capability, context.connection, context.peer);
             self.providers.remove(&capability);
         } => Success,
         // Status
@@ -64,20 +101,33 @@ router! {
 
 impl Registry {
     #[allow(dead_code)]
-    pub fn serve(path: &str) -> Result<(), Error> {
-        IpcServer::serve(path, Self::start_default())?;
+    pub fn serve(address: &str) -> Result<ServerHandle, Error> {
+        let config = ServerConfig::default().authorized(Self::policy());
 
-        Ok(())
+        IpcServer::serve_with(address, Supervisor::start(|_| Self::default()), config)
     }
 
     #[allow(dead_code)]
-    pub fn serve_default() -> Result<(), Error> {
-        let path = "/tmp/central.registry";
+    pub fn serve_default() -> Result<ServerHandle, Error> {
+        Self::serve_default_with(ServerConfig::default().authorized(Self::policy()))
+    }
+
+    /// Only providers running as the registry's own user may change what it hands out
+    pub fn policy() -> Policy {
+        Policy::default()
+            .method("Register", Allow::Owner)
+            .method("Deregister", Allow::Owner)
+    }
 
-        IpcServer::serve(path, Self::start_default())
-            .context(format!("Error serving on ipc path: {}", path))?;
+    #[allow(dead_code)]
+    pub fn serve_default_with(config: ServerConfig) -> Result<ServerHandle, Error> {
+        let address = registry_address();
 
-        Ok(())
+        let server =
+            IpcServer::serve_with(&address, Supervisor::start(|_| Self::default()), config)
+                .context(format!("Error serving on address: {}", address))?;
+
+        Ok(server)
     }
 }
 
@@ -96,9 +146,7 @@ client! {
 impl ProviderClient {
     #[allow(dead_code)]
     pub async fn register_default(capability: &str, address: &str) -> Result<Addr<Self>, Error> {
-        let path = "/tmp/central.registry";
-
-        let addr = ProviderClient::connect(path).await?;
+        let addr = ProviderClient::connect(&registry_address()).await?;
 
         addr.send(Register {
             capability: capability.to_string(),
@@ -111,9 +159,7 @@ impl ProviderClient {
 
     #[allow(dead_code)]
     pub async fn connect_default() -> Result<Addr<Self>, Error> {
-        let path = "/tmp/central.registry";
-
-        let addr = ProviderClient::connect(path).await?;
+        let addr = ProviderClient::connect(&registry_address()).await?;
 
         Ok(addr)
     }
@@ -128,8 +174,8 @@ client! {
             Capability { address } => [
                 Result<String, Error>: Ok(address)
             ],
-            Error { description } => [
-                Result<String, Error>: Err(format_err!("Error: {}", description))
+            Error { error } => [
+                Result<String, Error>: Err(error.into())
             ]
         ]
     }
@@ -149,19 +195,70 @@ client! {
 impl InterfaceClient {
     #[allow(dead_code)]
     pub async fn connect_default() -> Result<Addr<Self>, Error> {
-        InterfaceClient::connect("/tmp/central.registry").await
+        InterfaceClient::connect(&registry_address()).await
     }
 }
 
 impl StatusClient {
     #[allow(dead_code)]
     pub async fn check_default() -> Result<(), Error> {
-        let path = "/tmp/central.registry";
-
-        let addr = StatusClient::connect(path).await?;
+        let addr = StatusClient::connect(&registry_address()).await?;
 
         addr.send(Check).await?;
 
         Ok(())
     }
 }
+
+/// The capability every provider registers as `<provider>_metrics`, answering with what its
+/// routers have recorded
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct Metrics;
+
+impl Actor for Metrics {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Metrics {}
+
+router! {
+    Metrics [
+        Scrape -> {
+            let text = cliff::metrics::render();
+        } => Exposition [String] { text }
+    ]
+}
+
+client! {
+    Metrics {
+        actions => [
+            Scrape wait Result<String, Error>
+        ],
+        response_mapping => [
+            Exposition { text } => [
+                Result<String, Error>: Ok(text)
+            ],
+            Error { error } => [
+                Result<String, Error>: Err(error.into())
+            ]
+        ]
+    }
+}
+
+impl MetricsClient {
+    /// What `provider`'s routers have recorded, in Prometheus' text format
+    #[allow(dead_code)]
+    pub async fn scrape(provider: &str) -> Result<String, Error> {
+        let interface_client = InterfaceClient::connect_default().await?;
+        let address = interface_client
+            .send(Require {
+                capability: format!("{}_metrics", provider),
+            })
+            .await??;
+
+        let client = MetricsClient::connect(&address).await?;
+
+        client.send(Scrape).await?
+    }
+}
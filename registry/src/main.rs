@@ -4,9 +4,12 @@ use actix::prelude::*;
 
 use failure::Error;
 
-use crate::lib::Registry;
+use cliff::server::ServerConfig;
+use cliff::server::SHUTDOWN_DEADLINE;
 
-use tracing::{error, info, span, Level};
+use crate::lib::{shutdown_signal, Registry};
+
+use tracing::{error, span, Level};
 
 #[actix_rt::main]
 async fn main() -> Result<(), Error> {
@@ -21,10 +24,17 @@ async fn main() -> Result<(), Error> {
         let span = span!(Level::TRACE, "Registry Running");
         let _enter = span.enter();
 
-        Registry::serve_default()?;
-
-        tokio::signal::ctrl_c().await?;
-        info!("Ctrl-C received, shutting down");
+        // `--force` takes the address over from a registry that's still running
+        let config = ServerConfig::default().authorized(Registry::policy());
+        let config = if std::env::args().skip(1).any(|arg| arg == "--force") {
+            config.forced()
+        } else {
+            config
+        };
+        let server = Registry::serve_default_with(config)?;
+
+        shutdown_signal().await?;
+        server.shutdown(SHUTDOWN_DEADLINE).await?;
 
         Ok(())
     };